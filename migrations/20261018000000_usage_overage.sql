@@ -0,0 +1,2 @@
+-- The overage policy applied to a session which exceeded its allowance, i.e. `throttle:1000`, NULL otherwise.
+ALTER TABLE `Usage` ADD COLUMN `overage` VARCHAR(191) NULL;
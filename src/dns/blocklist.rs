@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::fs;
+use std::time::SystemTime;
+
+// Domains (and every name beneath them) the resolver answers NXDOMAIN for.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+    // Modification time of every file, as of the last load.
+    loaded: Vec<Option<SystemTime>>
+}
+
+impl Blocklist {
+    // Reads every file, skipping (and reporting) those which cannot be read.
+    pub fn load(paths: &[String]) -> Self {
+        let mut domains = HashSet::new();
+
+        for path in paths {
+            match fs::read_to_string(path) {
+                Ok(contents) => {
+                    let before = domains.len();
+                    domains.extend(contents.lines().filter_map(parse_line));
+                    println!("[dns]: Loaded {} blocked domains from {}", domains.len() - before, path);
+                },
+                Err(err) => println!("[err]: Unable to read blocklist {}, {}", path, err)
+            }
+        }
+
+        Blocklist {
+            domains,
+            loaded: modified(paths)
+        }
+    }
+
+    // Whether any file was changed, added or removed since it was loaded.
+    pub fn is_stale(&self, paths: &[String]) -> bool {
+        self.loaded != modified(paths)
+    }
+
+    pub fn blocks(&self, name: &str) -> bool {
+        if self.domains.is_empty() {
+            return false;
+        }
+
+        let name = name.trim_end_matches('.').to_lowercase();
+        let mut domain = name.as_str();
+
+        // `ads.example.com` is blocked by `ads.example.com`, `example.com` or `com`.
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.domains.len()
+    }
+}
+
+fn modified(paths: &[String]) -> Vec<Option<SystemTime>> {
+    paths.iter()
+        .map(| path | fs::metadata(path).and_then(| metadata | metadata.modified()).ok())
+        .collect()
+}
+
+// Either a bare domain or a hosts entry, i.e. `0.0.0.0 ads.example.com`, with `#` comments.
+fn parse_line(line: &str) -> Option<String> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let domain = line.split_whitespace().last()?;
+
+    match domain {
+        "localhost" | "localhost.localdomain" | "broadcasthost" | "local" => None,
+        domain if domain.parse::<std::net::IpAddr>().is_ok() => None,
+        domain => Some(domain.trim_end_matches('.').to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist(contents: &str) -> Blocklist {
+        Blocklist {
+            domains: contents.lines().filter_map(parse_line).collect(),
+            loaded: vec![]
+        }
+    }
+
+    #[test]
+    fn blocks_names_beneath_an_entry() {
+        let blocklist = blocklist("example.com");
+
+        assert!(blocklist.blocks("example.com"));
+        assert!(blocklist.blocks("ads.example.com"));
+        assert!(blocklist.blocks("a.b.example.com"));
+        assert!(!blocklist.blocks("notexample.com"));
+        assert!(!blocklist.blocks("com"));
+    }
+
+    #[test]
+    fn ignores_trailing_dots_and_case() {
+        let blocklist = blocklist("Tracker.Example.net.");
+
+        assert!(blocklist.blocks("tracker.example.net."));
+        assert!(blocklist.blocks("TRACKER.example.net"));
+        assert!(!blocklist.blocks("example.net."));
+    }
+
+    #[test]
+    fn parses_hosts_entries() {
+        assert_eq!(parse_line("0.0.0.0 ads.example.com"), Some("ads.example.com".to_string()));
+        assert_eq!(parse_line("127.0.0.1\tads.example.com  # served locally"), Some("ads.example.com".to_string()));
+        assert_eq!(parse_line("ads.example.com"), Some("ads.example.com".to_string()));
+    }
+
+    #[test]
+    fn skips_comments_and_local_entries() {
+        assert_eq!(parse_line("# ads.example.com"), None);
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("127.0.0.1 localhost"), None);
+        assert_eq!(parse_line("127.0.0.1 localhost.localdomain"), None);
+        assert_eq!(parse_line("255.255.255.255 broadcasthost"), None);
+        assert_eq!(parse_line("::1 local"), None);
+        assert_eq!(parse_line("0.0.0.0"), None);
+        assert_eq!(parse_line("::1"), None);
+
+        let blocklist = blocklist("# hosts\n127.0.0.1 localhost\n0.0.0.0 0.0.0.0\n0.0.0.0 ads.example.com");
+        assert_eq!(blocklist.count(), 1);
+        assert!(!blocklist.blocks("localhost"));
+    }
+}
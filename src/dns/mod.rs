@@ -0,0 +1,7 @@
+mod blocklist;
+mod resolver;
+mod server;
+
+pub use blocklist::*;
+pub use resolver::*;
+pub use server::*;
@@ -0,0 +1,88 @@
+use crate::types::{DnsConfig, DnsProtocol};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use hickory_server::authority::MessageResponseBuilder;
+use hickory_server::proto::op::{Header, MessageType, OpCode, ResponseCode};
+use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use super::Blocklist;
+
+// Answers clients queries from the configured upstreams, unless the name is blocked.
+pub struct Resolver {
+    upstream: TokioAsyncResolver,
+    blocklist: Arc<RwLock<Blocklist>>
+}
+
+impl Resolver {
+    pub fn new(config: &DnsConfig, blocklist: Arc<RwLock<Blocklist>>) -> Self {
+        let name_servers = match config.protocol {
+            DnsProtocol::Https => NameServerConfigGroup::from_ips_https(&config.upstreams, config.upstream_port, config.upstream_name.clone(), true),
+            DnsProtocol::Tls => NameServerConfigGroup::from_ips_tls(&config.upstreams, config.upstream_port, config.upstream_name.clone(), true),
+            DnsProtocol::Udp => NameServerConfigGroup::from_ips_clear(&config.upstreams, config.upstream_port, true)
+        };
+
+        Resolver {
+            upstream: TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], name_servers), ResolverOpts::default()),
+            blocklist
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for Resolver {
+    async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+        let builder = MessageResponseBuilder::from_message_request(request);
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_recursion_available(true);
+
+        if request.op_code() != OpCode::Query || request.message_type() != MessageType::Query {
+            let response = builder.error_msg(request.header(), ResponseCode::NotImp);
+            return sent(response_handle.send_response(response).await, header);
+        }
+
+        let query = request.query();
+
+        if self.blocklist.read().await.blocks(&query.name().to_string()) {
+            header.set_response_code(ResponseCode::NXDomain);
+            let response = builder.build_no_records(header);
+            return sent(response_handle.send_response(response).await, header);
+        }
+
+        match self.upstream.lookup(query.original().name().clone(), query.query_type()).await {
+            Ok(lookup) => {
+                let response = builder.build(header, lookup.records().iter(), std::iter::empty(), std::iter::empty(), std::iter::empty());
+                sent(response_handle.send_response(response).await, header)
+            },
+            Err(err) => {
+                match err.kind() {
+                    // NXDOMAIN, or the name exists without records of this type.
+                    ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+                        header.set_response_code(*response_code);
+                        let response = builder.build_no_records(header);
+                        sent(response_handle.send_response(response).await, header)
+                    },
+                    _ => {
+                        println!("[dns]: Unable to resolve {} {}, {}", query.name(), query.query_type(), err);
+                        let response = builder.error_msg(request.header(), ResponseCode::ServFail);
+                        sent(response_handle.send_response(response).await, header)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn sent(result: io::Result<ResponseInfo>, header: Header) -> ResponseInfo {
+    match result {
+        Ok(info) => info,
+        Err(err) => {
+            println!("[dns]: Unable to send response, {}", err);
+            header.into()
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::wireguard::WireGuard;
+use futures_timer::Delay;
+use hickory_server::ServerFuture;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::RwLock;
+
+use super::{Blocklist, Resolver};
+
+pub const DNS_PORT: u16 = 53;
+// How long a TCP client may keep a connection open without sending a query.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serves the resolver on the servers address of every interface, so it is only reachable through the tunnel.
+// Interfaces are up by the time this runs, as they are brought up before any task is spawned.
+pub async fn dns_server(config: WireGuard) {
+    let (settings, listen) = {
+        let lock = config.lock().await;
+
+        let listen: Vec<IpAddr> = lock.config.interfaces.iter()
+            .flat_map(| interface | interface.dns_addresses())
+            .collect();
+
+        (lock.config.resolver.clone(), listen)
+    };
+
+    if !settings.enabled {
+        println!("[dns]: Built-in resolver disabled");
+        return;
+    }
+
+    let blocklist = Arc::new(RwLock::new(Blocklist::load(&settings.blocklists)));
+    let mut server = ServerFuture::new(Resolver::new(&settings, blocklist.clone()));
+
+    for address in listen {
+        let address = SocketAddr::new(address, DNS_PORT);
+
+        match UdpSocket::bind(address).await {
+            Ok(socket) => server.register_socket(socket),
+            Err(err) => println!("[err]: Unable to bind dns on udp {}, {}", address, err)
+        }
+
+        match TcpListener::bind(address).await {
+            Ok(listener) => server.register_listener(listener, TCP_TIMEOUT),
+            Err(err) => println!("[err]: Unable to bind dns on tcp {}, {}", address, err)
+        }
+
+        println!("[dns]: Listening on {}", address);
+    }
+
+    if settings.blocklist_reload_seconds > 0 && !settings.blocklists.is_empty() {
+        tokio::spawn(reload_blocklists(blocklist, settings.blocklists.clone(), settings.blocklist_reload_seconds as u64));
+    }
+
+    if let Err(err) = server.block_until_done().await {
+        println!("[err]: DNS server stopped, {}", err);
+    }
+}
+
+// Replaces the blocklist whenever one of its files changes, queries keep using the previous list until then.
+async fn reload_blocklists(blocklist: Arc<RwLock<Blocklist>>, paths: Vec<String>, interval: u64) {
+    loop {
+        Delay::new(Duration::from_secs(interval)).await;
+
+        if !blocklist.read().await.is_stale(&paths) {
+            continue;
+        }
+
+        let reloaded = Blocklist::load(&paths);
+        println!("[dns]: Reloaded blocklists, {} domains blocked", reloaded.count());
+
+        *blocklist.write().await = reloaded;
+    }
+}
@@ -0,0 +1,32 @@
+use std::fs;
+
+// Used when no default route can be found, i.e. the previously hard-coded interface.
+pub const FALLBACK_EGRESS: &str = "eth0";
+
+// Finds the interface of the IPv4 default route in /proc/net/route. Columns are whitespace
+// separated, the first being the interface and the second the destination in hex, 00000000 for default.
+pub fn detect_egress() -> Option<String> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+
+    parse_default_route(&routes)
+}
+
+pub fn parse_default_route(routes: &str) -> Option<String> {
+    routes.lines()
+        .skip(1)
+        .filter_map(| line | {
+            let mut columns = line.split_whitespace();
+            let interface = columns.next()?;
+            let destination = columns.next()?;
+            let _gateway = columns.next()?;
+            let flags = u16::from_str_radix(columns.next()?, 16).ok()?;
+
+            // RTF_UP
+            if destination == "00000000" && flags & 0x1 != 0 {
+                Some(interface.to_string())
+            } else {
+                None
+            }
+        })
+        .next()
+}
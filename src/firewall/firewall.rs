@@ -0,0 +1,188 @@
+use crate::types::{FirewallBackend, FirewallConfig, InterfaceDefinition, PortForward};
+use std::net::Ipv4Addr;
+use crate::wireguard::WireGuardError;
+use std::process::Command;
+
+use super::{FALLBACK_EGRESS, Ruleset, ShapedPeer, detect_egress, iptables, meters, nftables, tc};
+
+// Generates the forwarding and NAT rules of every interface. They are installed and removed by the
+// interfaces own PostUp/PostDown hooks, so both run wherever the interface is brought up or down.
+#[derive(Debug, Clone)]
+pub struct Firewall {
+    pub backend: FirewallBackend,
+    pub egress: String,
+    pub client_isolation: bool,
+    pub dns_leak_protection: bool,
+    // Whether public ports may be forwarded to peers.
+    pub port_forwarding: bool,
+    // Whether peers are given exit addresses of their own.
+    pub exit_addresses: bool,
+    // Every interface on the node, isolation covers traffic between them.
+    pub interfaces: Vec<InterfaceDefinition>,
+    // Every rate a peer may be shaped to, nftables prepares a meter for each.
+    pub rates: Vec<u32>
+}
+
+impl Firewall {
+    pub fn from_config(config: &FirewallConfig, interfaces: &[InterfaceDefinition], rates: Vec<u32>, port_forwarding: bool, exit_addresses: bool) -> Self {
+        let egress = match &config.egress_interface {
+            Some(egress) => egress.clone(),
+            None => match detect_egress() {
+                Some(egress) => egress,
+                None => {
+                    println!("[firewall]: Unable to detect the egress interface, falling back to {}", FALLBACK_EGRESS);
+                    FALLBACK_EGRESS.to_string()
+                }
+            }
+        };
+
+        println!(
+            "[firewall]: Using {:?} with egress through {}, client isolation {}, dns leak protection {}",
+            config.backend, egress,
+            if config.client_isolation { "on" } else { "off" },
+            if config.dns_leak_protection { "on" } else { "off" }
+        );
+
+        Firewall {
+            backend: config.backend,
+            egress,
+            client_isolation: config.client_isolation,
+            dns_leak_protection: config.dns_leak_protection,
+            port_forwarding,
+            exit_addresses,
+            interfaces: interfaces.to_vec(),
+            rates
+        }
+    }
+
+    pub fn ruleset(&self, interface: &InterfaceDefinition) -> Ruleset {
+        Ruleset::for_interface(interface, self)
+    }
+
+    pub fn up_commands(&self, interface: &InterfaceDefinition) -> Vec<String> {
+        let ruleset = self.ruleset(interface);
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => {
+                let mut commands = iptables::up(&ruleset);
+                commands.extend(tc::setup(&interface.name));
+                commands
+            },
+            FirewallBackend::Nftables => nftables::up(&ruleset, &self.rates)
+        }
+    }
+
+    pub fn down_commands(&self, interface: &InterfaceDefinition) -> Vec<String> {
+        let ruleset = self.ruleset(interface);
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => {
+                let mut commands = iptables::down(&ruleset);
+                commands.extend(tc::teardown(&interface.name));
+                commands
+            },
+            FirewallBackend::Nftables => nftables::down(&ruleset)
+        }
+    }
+
+    // Teardown runs first, so bringing an interface up twice leaves one copy of every rule.
+    pub fn post_up(&self, interface: &InterfaceDefinition) -> String {
+        let mut commands = self.down_commands(interface);
+        commands.extend(self.up_commands(interface));
+
+        if interface.ipv6_prefix.is_some() {
+            commands.insert(0, "sysctl -w net.ipv6.conf.all.forwarding=1".to_string());
+        }
+
+        commands.join("; ")
+    }
+
+    pub fn post_down(&self, interface: &InterfaceDefinition) -> String {
+        self.down_commands(interface).join("; ")
+    }
+
+    // Limits a peer to its tiers rate, replacing any previous limit on its slot.
+    pub fn shape_peer(&self, peer: &ShapedPeer) -> Result<(), WireGuardError> {
+        match self.backend {
+            FirewallBackend::IptablesLegacy => Firewall::run(&tc::shape(peer)),
+            FirewallBackend::Nftables => Firewall::run(&meters::shape(&self.table(&peer.interface), peer, &self.rates))
+        }
+    }
+
+    pub fn unshape_peer(&self, peer: &ShapedPeer) -> Result<(), WireGuardError> {
+        match self.backend {
+            FirewallBackend::IptablesLegacy => Firewall::run(&tc::unshape(peer)),
+            FirewallBackend::Nftables => Firewall::run(&meters::unshape(&self.table(&peer.interface), peer, &self.rates))
+        }
+    }
+
+    // Forwards the public port (tcp and udp) on the egress interface to the same port of the peer.
+    pub fn forward_port(&self, forward: &PortForward) -> Result<(), WireGuardError> {
+        let ruleset = Ruleset { interface: forward.interface.clone(), rules: vec![] };
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => {
+                // Removed first, so forwarding a port twice leaves a single rule.
+                let mut commands = iptables::unforward_port(&ruleset, &self.egress, forward);
+                commands.extend(iptables::forward_port(&ruleset, &self.egress, forward));
+                Firewall::run(&commands)
+            },
+            FirewallBackend::Nftables => Firewall::run(&nftables::forward_port(&self.table(&forward.interface), forward))
+        }
+    }
+
+    pub fn unforward_port(&self, forward: &PortForward) -> Result<(), WireGuardError> {
+        let ruleset = Ruleset { interface: forward.interface.clone(), rules: vec![] };
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => Firewall::run(&iptables::unforward_port(&ruleset, &self.egress, forward)),
+            FirewallBackend::Nftables => Firewall::run(&nftables::unforward_port(&self.table(&forward.interface), forward))
+        }
+    }
+
+    // Rewrites the source of a peers traffic leaving the node to `exit`, replacing the masquerade.
+    pub fn snat_peer(&self, interface: &str, source: Ipv4Addr, exit: Ipv4Addr) -> Result<(), WireGuardError> {
+        let ruleset = Ruleset { interface: interface.to_string(), rules: vec![] };
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => {
+                let mut commands = iptables::unsnat_peer(&ruleset, &self.egress, source, exit);
+                commands.extend(iptables::snat_peer(&ruleset, &self.egress, source, exit));
+                Firewall::run(&commands)
+            },
+            FirewallBackend::Nftables => Firewall::run(&nftables::snat_peer(&self.table(interface), source, exit))
+        }
+    }
+
+    pub fn unsnat_peer(&self, interface: &str, source: Ipv4Addr, exit: Ipv4Addr) -> Result<(), WireGuardError> {
+        let ruleset = Ruleset { interface: interface.to_string(), rules: vec![] };
+
+        match self.backend {
+            FirewallBackend::IptablesLegacy => Firewall::run(&iptables::unsnat_peer(&ruleset, &self.egress, source, exit)),
+            FirewallBackend::Nftables => Firewall::run(&nftables::unsnat_peer(&self.table(interface), source))
+        }
+    }
+
+    fn table(&self, interface: &str) -> String {
+        nftables::table(&Ruleset { interface: interface.to_string(), rules: vec![] })
+    }
+
+    pub fn run(commands: &[String]) -> Result<(), WireGuardError> {
+        for command in commands {
+            let output = Command::new("sh")
+                .args(["-c", command])
+                .output()
+                .map_err(| source | WireGuardError::Spawn { command: command.to_string(), source })?;
+
+            if !output.status.success() {
+                return Err(WireGuardError::Command {
+                    command: command.to_string(),
+                    status: output.status.code(),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string()
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::dns::DNS_PORT;
+use crate::types::PortForward;
+use std::net::{IpAddr, Ipv4Addr};
+
+use super::{Family, Rule, Ruleset};
+
+// Each interface owns a `RESEDA-<interface>` chain in the filter and nat tables, jumped to once from
+// FORWARD and POSTROUTING, and a `RESEDA-<interface>-PRE` chain jumped to from PREROUTING. Teardown removes
+// the jumps and the chains, so it never touches rules it did not add, and every step tolerates the chain
+// already being gone.
+
+fn chain(ruleset: &Ruleset) -> String {
+    format!("RESEDA-{}", ruleset.interface)
+}
+
+fn prerouting_chain(ruleset: &Ruleset) -> String {
+    format!("RESEDA-{}-PRE", ruleset.interface)
+}
+
+// Every (table, hook, chain) the interface jumps to.
+fn hooks(ruleset: &Ruleset) -> [(&'static str, &'static str, String); 3] {
+    [
+        ("filter", "FORWARD", chain(ruleset)),
+        ("nat", "POSTROUTING", chain(ruleset)),
+        ("nat", "PREROUTING", prerouting_chain(ruleset))
+    ]
+}
+
+fn binaries(ruleset: &Ruleset) -> Vec<(Family, &'static str)> {
+    let mut binaries = vec![(Family::Ipv4, "iptables-legacy")];
+
+    if ruleset.uses_ipv6() {
+        binaries.push((Family::Ipv6, "ip6tables-legacy"));
+    }
+
+    binaries
+}
+
+pub fn up(ruleset: &Ruleset) -> Vec<String> {
+    let chain = chain(ruleset);
+    let prerouting = prerouting_chain(ruleset);
+    let mut commands = vec![];
+
+    for (family, binary) in binaries(ruleset) {
+        for (table, hook, chain) in hooks(ruleset) {
+            commands.push(format!("{binary} -w -t {table} -N {chain} 2>/dev/null || {binary} -w -t {table} -F {chain}", binary = binary, table = table, chain = chain));
+            commands.push(format!("{binary} -w -t {table} -C {hook} -j {chain} 2>/dev/null || {binary} -w -t {table} -A {hook} -j {chain}", binary = binary, table = table, hook = hook, chain = chain));
+        }
+
+        for rule in &ruleset.rules {
+            let rule = match rule {
+                Rule::RedirectDns { interface, family: rule_family, protocol, resolver } if *rule_family == family => {
+                    let destination = match resolver {
+                        IpAddr::V4(address) => format!("{}:{}", address, DNS_PORT),
+                        IpAddr::V6(address) => format!("[{}]:{}", address, DNS_PORT)
+                    };
+
+                    format!("-t nat -A {} -i {} -p {} --dport {} -j DNAT --to-destination {}", prerouting, interface, protocol, DNS_PORT, destination)
+                },
+                Rule::RedirectDns { .. } => continue,
+                // Each peer is given its exit address by its own rule, see `snat_peer`.
+                Rule::SnatPeers { .. } => continue,
+                // Each port is forwarded by its own rule, see `forward_port`.
+                Rule::ForwardPorts { .. } => continue,
+                Rule::AcceptForwarded { interface } => format!("-t filter -A {} -o {} -m conntrack --ctstate DNAT -j ACCEPT", chain, interface),
+                Rule::RejectPort { interface, protocol, port } => format!("-t filter -A {} -i {} -p {} --dport {} -j REJECT", chain, interface, protocol, port),
+                Rule::DropToPeers { interface, family: rule_family, destination } if *rule_family == family => {
+                    format!("-t filter -A {} -i {} -d {} -j DROP", chain, interface, destination)
+                },
+                Rule::DropToPeers { .. } => continue,
+                Rule::ForwardFrom { interface } => format!("-t filter -A {} -i {} -j ACCEPT", chain, interface),
+                Rule::ForwardReplies { interface } => format!("-t filter -A {} -o {} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT", chain, interface),
+                Rule::Masquerade { family: rule_family, source, egress } if *rule_family == family => {
+                    format!("-t nat -A {} -s {} -o {} -j MASQUERADE", chain, source, egress)
+                },
+                Rule::Masquerade { .. } => continue
+            };
+
+            commands.push(format!("{} -w {}", binary, rule));
+        }
+    }
+
+    commands
+}
+
+pub fn down(ruleset: &Ruleset) -> Vec<String> {
+    let mut commands = vec![];
+
+    // Both families are always torn down, IPv6 may have been enabled when the rules were added.
+    for binary in ["iptables-legacy", "ip6tables-legacy"] {
+        for (table, hook, chain) in hooks(ruleset) {
+            commands.push(format!("{binary} -w -t {table} -D {hook} -j {chain} 2>/dev/null || true", binary = binary, table = table, hook = hook, chain = chain));
+            commands.push(format!("{binary} -w -t {table} -F {chain} 2>/dev/null || true", binary = binary, table = table, chain = chain));
+            commands.push(format!("{binary} -w -t {table} -X {chain} 2>/dev/null || true", binary = binary, table = table, chain = chain));
+        }
+    }
+
+    commands
+}
+
+// Rules of a forwarded port live in the interfaces prerouting chain, so they go with the interface.
+// `action` is -A to add them or -D to delete them.
+fn forward_rules(ruleset: &Ruleset, egress: &str, forward: &PortForward, action: &str) -> Vec<String> {
+    ["tcp", "udp"].iter()
+        .map(| protocol | format!(
+            "iptables-legacy -w -t nat {} {} -i {} -p {} --dport {} -j DNAT --to-destination {}",
+            action, prerouting_chain(ruleset), egress, protocol, forward.port, forward.address
+        ))
+        .collect()
+}
+
+pub fn forward_port(ruleset: &Ruleset, egress: &str, forward: &PortForward) -> Vec<String> {
+    forward_rules(ruleset, egress, forward, "-A")
+}
+
+pub fn unforward_port(ruleset: &Ruleset, egress: &str, forward: &PortForward) -> Vec<String> {
+    forward_rules(ruleset, egress, forward, "-D").into_iter()
+        .map(| rule | format!("{} 2>/dev/null || true", rule))
+        .collect()
+}
+
+// Inserted ahead of the interfaces masquerade, which would otherwise match first.
+pub fn snat_peer(ruleset: &Ruleset, egress: &str, source: Ipv4Addr, exit: Ipv4Addr) -> Vec<String> {
+    vec![format!("iptables-legacy -w -t nat -I {} 1 -s {}/32 -o {} -j SNAT --to-source {}", chain(ruleset), source, egress, exit)]
+}
+
+pub fn unsnat_peer(ruleset: &Ruleset, egress: &str, source: Ipv4Addr, exit: Ipv4Addr) -> Vec<String> {
+    vec![format!("iptables-legacy -w -t nat -D {} -s {}/32 -o {} -j SNAT --to-source {} 2>/dev/null || true", chain(ruleset), source, egress, exit)]
+}
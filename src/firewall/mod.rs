@@ -0,0 +1,11 @@
+mod firewall;
+mod rules;
+mod egress;
+mod iptables;
+mod nftables;
+mod shaping;
+
+pub use firewall::*;
+pub use rules::*;
+pub use egress::*;
+pub use shaping::*;
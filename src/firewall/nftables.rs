@@ -0,0 +1,126 @@
+use crate::dns::DNS_PORT;
+use crate::types::PortForward;
+use std::net::{IpAddr, Ipv4Addr};
+
+use super::{Family, Rule, Ruleset, meters};
+
+// Each interface owns an `inet reseda_<interface>` table. Bringing it up flushes and refills the table,
+// tearing it down deletes the table, which removes exactly what was added.
+
+pub fn table(ruleset: &Ruleset) -> String {
+    let name: String = ruleset.interface.chars()
+        .map(| c | if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    format!("inet reseda_{}", name)
+}
+
+fn nft_family(family: Family) -> &'static str {
+    match family {
+        Family::Ipv4 => "ip",
+        Family::Ipv6 => "ip6"
+    }
+}
+
+pub fn up(ruleset: &Ruleset, rates: &[u32]) -> Vec<String> {
+    let table = table(ruleset);
+
+    let mut commands = vec![
+        format!("nft add table {}", table),
+        format!("nft flush table {}", table),
+        format!("nft add chain {} forward '{{ type filter hook forward priority 0; policy accept; }}'", table),
+        format!("nft add chain {} postrouting '{{ type nat hook postrouting priority 100; policy accept; }}'", table),
+        format!("nft add chain {} prerouting '{{ type nat hook prerouting priority -100; policy accept; }}'", table)
+    ];
+
+    // Meters drop over-limit traffic, so they must come before anything accepts it.
+    commands.extend(meters::setup(&table, rates));
+
+    for rule in &ruleset.rules {
+        let rule = match rule {
+            Rule::RedirectDns { interface, family, protocol, resolver } => {
+                let destination = match resolver {
+                    IpAddr::V4(address) => format!("{}:{}", address, DNS_PORT),
+                    IpAddr::V6(address) => format!("[{}]:{}", address, DNS_PORT)
+                };
+
+                let nfproto = match family {
+                    Family::Ipv4 => "ipv4",
+                    Family::Ipv6 => "ipv6"
+                };
+
+                format!(
+                    "prerouting iifname {} meta nfproto {} {} dport {} dnat {} to {}",
+                    interface, nfproto, protocol, DNS_PORT, nft_family(*family), destination
+                )
+            },
+            // Peers exit addresses are elements of a map, peers missing from it are left to the masquerade.
+            Rule::SnatPeers { egress } => {
+                commands.push(format!("nft add map {} {} '{{ type ipv4_addr : ipv4_addr; }}'", table, SNAT_MAP));
+                format!("postrouting oifname {} meta nfproto ipv4 snat ip to ip saddr map @{}", egress, SNAT_MAP)
+            },
+            // Forwarded ports are elements of a map per protocol, the rule dnats anything found in them.
+            Rule::ForwardPorts { egress } => {
+                for protocol in ["tcp", "udp"] {
+                    commands.push(format!("nft add map {} {} '{{ type inet_service : ipv4_addr; }}'", table, forward_map(protocol)));
+                    commands.push(format!(
+                        "nft add rule {} prerouting iifname {} meta nfproto ipv4 dnat ip to {} dport map @{}",
+                        table, egress, protocol, forward_map(protocol)
+                    ));
+                }
+
+                continue;
+            },
+            Rule::AcceptForwarded { interface } => format!("forward oifname {} ct status dnat accept", interface),
+            Rule::RejectPort { interface, protocol, port } => format!("forward iifname {} {} dport {} reject", interface, protocol, port),
+            Rule::DropToPeers { interface, family, destination } => {
+                format!("forward iifname {} {} daddr {} drop", interface, nft_family(*family), destination)
+            },
+            Rule::ForwardFrom { interface } => format!("forward iifname {} accept", interface),
+            Rule::ForwardReplies { interface } => format!("forward oifname {} ct state related,established accept", interface),
+            Rule::Masquerade { family, source, egress } => {
+                format!("postrouting {} saddr {} oifname {} masquerade", nft_family(*family), source, egress)
+            }
+        };
+
+        commands.push(format!("nft add rule {} {}", table, rule));
+    }
+
+    commands
+}
+
+const SNAT_MAP: &str = "exit_addresses";
+
+pub fn snat_peer(table: &str, source: Ipv4Addr, exit: Ipv4Addr) -> Vec<String> {
+    let mut commands = unsnat_peer(table, source);
+    commands.push(format!("nft add element {} {} '{{ {} : {} }}'", table, SNAT_MAP, source, exit));
+    commands
+}
+
+pub fn unsnat_peer(table: &str, source: Ipv4Addr) -> Vec<String> {
+    vec![format!("nft delete element {} {} '{{ {} }}' 2>/dev/null || true", table, SNAT_MAP, source)]
+}
+
+fn forward_map(protocol: &str) -> String {
+    format!("forwards_{}", protocol)
+}
+
+pub fn forward_port(table: &str, forward: &PortForward) -> Vec<String> {
+    let mut commands = unforward_port(table, forward);
+
+    for protocol in ["tcp", "udp"] {
+        commands.push(format!("nft add element {} {} '{{ {} : {} }}'", table, forward_map(protocol), forward.port, forward.address));
+    }
+
+    commands
+}
+
+pub fn unforward_port(table: &str, forward: &PortForward) -> Vec<String> {
+    ["tcp", "udp"].iter()
+        .map(| protocol | format!("nft delete element {} {} '{{ {} }}' 2>/dev/null || true", table, forward_map(protocol), forward.port))
+        .collect()
+}
+
+pub fn down(ruleset: &Ruleset) -> Vec<String> {
+    vec![format!("nft delete table {} 2>/dev/null || true", table(ruleset))]
+}
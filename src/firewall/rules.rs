@@ -0,0 +1,114 @@
+use crate::types::InterfaceDefinition;
+use std::net::IpAddr;
+
+use super::Firewall;
+
+// DNS-over-TLS (RFC 7858).
+const DOT_PORT: u16 = 853;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    Ipv4,
+    Ipv6
+}
+
+// A single forwarding or NAT rule, independent of the tool which installs it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    // Send peer DNS (to any server) to the built-in resolver instead, so it cannot leak past the tunnel.
+    RedirectDns { interface: String, family: Family, protocol: &'static str, resolver: IpAddr },
+    // Reject peer traffic to a port, i.e. DNS-over-TLS which cannot be redirected.
+    RejectPort { interface: String, protocol: &'static str, port: u16 },
+    // Rewrite the source of peer traffic leaving the node to the peers own exit address, see `Firewall::snat_peer`.
+    SnatPeers { egress: String },
+    // Public ports forwarded to peers arrive on the egress interface, see `Firewall::forward_port`.
+    ForwardPorts { egress: String },
+    // Forward new connections to peers, as long as they arrived through a forwarded port.
+    AcceptForwarded { interface: String },
+    // Drop peer traffic addressed to another peer. Traffic to the nodes own addresses is delivered
+    // locally rather than forwarded, so it is unaffected.
+    DropToPeers { interface: String, family: Family, destination: String },
+    // Forward everything peers send through the interface.
+    ForwardFrom { interface: String },
+    // Forward replies to connections peers opened.
+    ForwardReplies { interface: String },
+    // Rewrite the source of peer traffic leaving the node to the egress address.
+    Masquerade { family: Family, source: String, egress: String }
+}
+
+// Everything installed for one interface. Rules are installed in order.
+#[derive(Debug, Clone)]
+pub struct Ruleset {
+    pub interface: String,
+    pub rules: Vec<Rule>
+}
+
+impl Ruleset {
+    // Isolation covers every interface of the firewall, as peers on one must not reach peers on another either.
+    pub fn for_interface(interface: &InterfaceDefinition, firewall: &Firewall) -> Self {
+        let egress = firewall.egress.as_str();
+        let mut rules = vec![];
+
+        if firewall.dns_leak_protection {
+            for resolver in interface.dns_addresses() {
+                let family = match resolver {
+                    IpAddr::V4(_) => Family::Ipv4,
+                    IpAddr::V6(_) => Family::Ipv6
+                };
+
+                for protocol in ["udp", "tcp"] {
+                    rules.push(Rule::RedirectDns { interface: interface.name.clone(), family, protocol, resolver });
+                }
+            }
+
+            for protocol in ["tcp", "udp"] {
+                rules.push(Rule::RejectPort { interface: interface.name.clone(), protocol, port: DOT_PORT });
+            }
+        }
+
+        if firewall.client_isolation {
+            for other in &firewall.interfaces {
+                rules.push(Rule::DropToPeers { interface: interface.name.clone(), family: Family::Ipv4, destination: other.ipv4_network() });
+
+                if let Some(prefix) = &other.ipv6_prefix {
+                    rules.push(Rule::DropToPeers { interface: interface.name.clone(), family: Family::Ipv6, destination: prefix.to_string() });
+                }
+            }
+        }
+
+        if firewall.port_forwarding {
+            rules.extend([
+                Rule::ForwardPorts { egress: egress.to_string() },
+                Rule::AcceptForwarded { interface: interface.name.clone() }
+            ]);
+        }
+
+        rules.extend([
+            Rule::ForwardFrom { interface: interface.name.clone() },
+            Rule::ForwardReplies { interface: interface.name.clone() }
+        ]);
+
+        // Peers without an exit address of their own fall through to the masquerade.
+        if firewall.exit_addresses {
+            rules.push(Rule::SnatPeers { egress: egress.to_string() });
+        }
+
+        rules.extend([
+            Rule::Masquerade { family: Family::Ipv4, source: interface.ipv4_network(), egress: egress.to_string() }
+        ]);
+
+        if let Some(prefix) = &interface.ipv6_prefix {
+            rules.push(Rule::Masquerade { family: Family::Ipv6, source: prefix.to_string(), egress: egress.to_string() });
+        }
+
+        Ruleset {
+            interface: interface.name.clone(),
+            rules
+        }
+    }
+
+    // Forwarding rules are family independent, so they are mirrored into IPv6 when it is in use.
+    pub fn uses_ipv6(&self) -> bool {
+        self.rules.iter().any(| rule | matches!(rule, Rule::Masquerade { family: Family::Ipv6, .. } | Rule::DropToPeers { family: Family::Ipv6, .. } | Rule::RedirectDns { family: Family::Ipv6, .. }))
+    }
+}
@@ -0,0 +1,142 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// A peer whose traffic is limited to `kbit` in each direction.
+#[derive(Debug, Clone)]
+pub struct ShapedPeer {
+    pub interface: String,
+    // Unique per interface, derived from the peers slot.
+    pub id: u16,
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Option<Ipv6Addr>,
+    pub kbit: u32
+}
+
+// Roughly 100ms of traffic, but never less than a few full sized packets.
+fn burst_kbytes(kbit: u32) -> u32 {
+    (kbit / 80).max(32)
+}
+
+// With iptables, peers are shaped with tc on the interface itself. Traffic to a peer is classified into its own
+// HTB class, traffic from a peer is policed on ingress. Filters of a peer share its id as priority, so they
+// can be removed without knowing their handles.
+pub mod tc {
+    use super::{ShapedPeer, burst_kbytes};
+
+    // Unclassified traffic, i.e. unshaped peers. Never a peer id: ids are the last two octets of a peers address,
+    // and an address ending in .255.255 is the broadcast address of any pool holding it, see `AddressPool`.
+    const DEFAULT_CLASS: &str = "ffff";
+
+    pub fn setup(interface: &str) -> Vec<String> {
+        vec![
+            format!("tc qdisc replace dev {} root handle 1: htb default {}", interface, DEFAULT_CLASS),
+            format!("tc class replace dev {} parent 1: classid 1:{} htb rate 10gbit", interface, DEFAULT_CLASS),
+            format!("tc qdisc replace dev {} handle ffff: ingress", interface)
+        ]
+    }
+
+    pub fn teardown(interface: &str) -> Vec<String> {
+        vec![
+            format!("tc qdisc del dev {} root 2>/dev/null || true", interface),
+            format!("tc qdisc del dev {} ingress 2>/dev/null || true", interface)
+        ]
+    }
+
+    pub fn shape(peer: &ShapedPeer) -> Vec<String> {
+        let mut commands = unshape(peer);
+
+        commands.push(format!(
+            "tc class replace dev {} parent 1: classid 1:{:x} htb rate {kbit}kbit ceil {kbit}kbit",
+            peer.interface, peer.id, kbit = peer.kbit
+        ));
+
+        let mut matches = vec![("ip", format!("ip dst {}/32", peer.ipv4), format!("ip src {}/32", peer.ipv4))];
+
+        if let Some(ipv6) = peer.ipv6 {
+            matches.push(("ipv6", format!("ip6 dst {}/128", ipv6), format!("ip6 src {}/128", ipv6)));
+        }
+
+        for (protocol, destination, source) in matches {
+            commands.push(format!(
+                "tc filter add dev {} parent 1: protocol {} prio {} u32 match {} flowid 1:{:x}",
+                peer.interface, protocol, peer.id, destination, peer.id
+            ));
+            commands.push(format!(
+                "tc filter add dev {} parent ffff: protocol {} prio {} u32 match {} police rate {}kbit burst {}k drop flowid :1",
+                peer.interface, protocol, peer.id, source, peer.kbit, burst_kbytes(peer.kbit)
+            ));
+        }
+
+        commands
+    }
+
+    pub fn unshape(peer: &ShapedPeer) -> Vec<String> {
+        vec![
+            format!("tc filter del dev {} parent 1: prio {} 2>/dev/null || true", peer.interface, peer.id),
+            format!("tc filter del dev {} parent ffff: prio {} 2>/dev/null || true", peer.interface, peer.id),
+            format!("tc class del dev {} classid 1:{:x} 2>/dev/null || true", peer.interface, peer.id)
+        ]
+    }
+}
+
+// With nftables, every shaped rate has an address set in the interfaces table, and a meter which gives each
+// address in the set its own token bucket. Shaping a peer only adds its addresses to the set of its rate.
+pub mod meters {
+    use super::{ShapedPeer, burst_kbytes};
+
+    fn set(kbit: u32, family: &str) -> String {
+        format!("limit_{}_{}", kbit, family)
+    }
+
+    // Run within the interfaces table, after its forward chain exists.
+    pub fn setup(table: &str, rates: &[u32]) -> Vec<String> {
+        let mut commands = vec![];
+
+        for kbit in rates.iter().copied() {
+            // Meters are expressed in bytes.
+            let rate = format!("{} kbytes/second burst {} kbytes", kbit / 8, burst_kbytes(kbit));
+
+            for (family, kind) in [("ip", "ipv4_addr"), ("ip6", "ipv6_addr")] {
+                let set = set(kbit, family);
+
+                commands.push(format!("nft add set {} {} '{{ type {}; }}'", table, set, kind));
+                commands.push(format!(
+                    "nft add rule {table} forward {family} daddr @{set} meter {set}_down '{{ {family} daddr limit rate over {rate} }}' drop",
+                    table = table, family = family, set = set, rate = rate
+                ));
+                commands.push(format!(
+                    "nft add rule {table} forward {family} saddr @{set} meter {set}_up '{{ {family} saddr limit rate over {rate} }}' drop",
+                    table = table, family = family, set = set, rate = rate
+                ));
+            }
+        }
+
+        commands
+    }
+
+    pub fn shape(table: &str, peer: &ShapedPeer, rates: &[u32]) -> Vec<String> {
+        let mut commands = unshape(table, peer, rates);
+
+        commands.push(format!("nft add element {} {} '{{ {} }}'", table, set(peer.kbit, "ip"), peer.ipv4));
+
+        if let Some(ipv6) = peer.ipv6 {
+            commands.push(format!("nft add element {} {} '{{ {} }}'", table, set(peer.kbit, "ip6"), ipv6));
+        }
+
+        commands
+    }
+
+    // The peer may have been shaped at a different rate, so it is removed from every set.
+    pub fn unshape(table: &str, peer: &ShapedPeer, rates: &[u32]) -> Vec<String> {
+        let mut commands = vec![];
+
+        for kbit in rates.iter().copied() {
+            commands.push(format!("nft delete element {} {} '{{ {} }}' 2>/dev/null || true", table, set(kbit, "ip"), peer.ipv4));
+
+            if let Some(ipv6) = peer.ipv6 {
+                commands.push(format!("nft delete element {} {} '{{ {} }}' 2>/dev/null || true", table, set(kbit, "ip6"), ipv6));
+            }
+        }
+
+        commands
+    }
+}
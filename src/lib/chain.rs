@@ -0,0 +1,84 @@
+use crate::{types::{ExitNodeLostNotice, ExitNodeNotice, ServerMessage}, wireguard::{ExitRequest, ExitSocket, WireGuard, relayed_message, reopen_exit_session}};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::close_query;
+
+// Holds the session a chained client has with its exit node for as long as its own session is open, relaying
+// the exit nodes key rotations to it. A session which ended is reopened, failing that the client is told and
+// its session closed, rather than left without a way out.
+pub async fn hold_exit_session(config: WireGuard, client_id: String, request: ExitRequest, mut socket: ExitSocket) {
+    loop {
+        let reason = relay_exit_session(&config, &client_id, &mut socket).await;
+        println!("[chain]: Session of {} with exit {} ended, {}", client_id, request.exit.name, reason);
+
+        let session = match reopen_exit_session(&request).await {
+            Ok(session) => session,
+            Err(err) => {
+                println!("[err]: Unable to reopen session of {} with exit {}, closing it. Reason: {}", client_id, request.exit.name, err);
+
+                let mut configuration = config.lock().await;
+
+                // Detached first, closing the session would otherwise abort this task part way through `close_query`.
+                configuration.detach_exit_session(&client_id);
+
+                if let Some(client) = configuration.clients.lock().await.get(&client_id) {
+                    client.send_message(&ServerMessage::ExitNodeLost(ExitNodeLostNotice {
+                        exit_node: request.exit.name.clone(),
+                        reason: err.to_string()
+                    }));
+                }
+
+                close_query(&client_id, configuration).await;
+                return;
+            }
+        };
+
+        let mut configuration = config.lock().await;
+        let clients = configuration.clients.clone();
+        let mut clients = clients.lock().await;
+
+        if let Some(client) = clients.get_mut(&client_id) {
+            // The exit node may now answer from another address, which the outer peer has to route.
+            client.allowed_ips = configuration.chained_allowed_ips(client, session.endpoint);
+            configuration.update_exit_session(&client_id, session.route.clone(), session.endpoint);
+
+            client.send_message(&ServerMessage::ExitNode(ExitNodeNotice {
+                exit_node: session.route.clone(),
+                allowed_ips: client.allowed_ips.iter().map(| network | network.to_string()).collect()
+            }));
+        }
+
+        println!("[chain]: Reopened session of {} with exit {} at {}", client_id, request.exit.name, session.endpoint);
+        socket = session.socket;
+    }
+}
+
+// Relays until the session ends, returning why.
+async fn relay_exit_session(config: &WireGuard, client_id: &str, socket: &mut ExitSocket) -> String {
+    while let Some(message) = socket.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(_) => continue,
+            Err(err) => return err.to_string()
+        };
+
+        let message = match relayed_message(&text) {
+            Some(message) => message,
+            None => continue
+        };
+
+        // Acknowledged for the client, which follows the rotation once it is complete.
+        if let ServerMessage::ExitRekey(_) = message {
+            if let Err(err) = socket.send(Message::Text("{ \"query_type\": \"rekey\" }".to_string())).await {
+                return err.to_string();
+            }
+        }
+
+        if let Some(client) = config.lock().await.clients.lock().await.get(client_id) {
+            client.send_message(&message);
+        }
+    }
+
+    "closed by the exit node".to_string()
+}
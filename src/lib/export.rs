@@ -0,0 +1,81 @@
+use std::convert::Infallible;
+use std::io::Cursor;
+
+use crate::{types::{ConfigFormat, ConfigRequest}, wireguard::{WireGuard, is_key_of}};
+use image::{ImageFormat, Luma};
+use qrcode::{QrCode, render::{svg, unicode}};
+use warp::http::StatusCode;
+use warp::reply::json as json_reply;
+use warp::Reply;
+
+// Serves the config of an open session, authenticated by the `config_token` of its open reply given as
+// `Authorization: Bearer <token>`, i.e. so the session can be scanned into the WireGuard mobile apps.
+pub async fn client_config_handler(authorization: String, request: ConfigRequest, config: WireGuard) -> Result<Box<dyn Reply>, Infallible> {
+    let token = match authorization.strip_prefix("Bearer ") {
+        Some(token) if !token.trim().is_empty() => token.trim(),
+        _ => return Ok(error_reply(StatusCode::UNAUTHORIZED, "Missing bearer token."))
+    };
+
+    let contents = {
+        let data = config.lock().await;
+        let clients = data.clients.lock().await;
+
+        let client = match clients.values().find(| client | client.config_token.as_deref() == Some(token)) {
+            Some(client) => client,
+            None => return Ok(error_reply(StatusCode::UNAUTHORIZED, "Unknown or expired token."))
+        };
+
+        if let Some(private_key) = &request.private_key {
+            if !is_key_of(client, private_key) {
+                return Ok(error_reply(StatusCode::FORBIDDEN, "Private key does not belong to this session."));
+            }
+        }
+
+        match data.client_config(client, request.private_key.as_deref()) {
+            Some(client_config) => client_config.to_string(),
+            None => return Ok(error_reply(StatusCode::NOT_FOUND, "Session is not connected."))
+        }
+    };
+
+    match render_config(&contents, request.format) {
+        Ok((body, content_type)) => Ok(Box::new(warp::reply::with_header(body, "content-type", content_type))),
+        Err(err) => {
+            println!("[err]: Unable to render client config as {:?}, {}", request.format, err);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to render config."))
+        }
+    }
+}
+
+fn render_config(contents: &str, format: ConfigFormat) -> Result<(Vec<u8>, &'static str), String> {
+    let code = || QrCode::new(contents.as_bytes()).map_err(| err | err.to_string());
+
+    match format {
+        ConfigFormat::Conf => Ok((contents.as_bytes().to_vec(), "text/plain; charset=utf-8")),
+        ConfigFormat::Png => {
+            let image = code()?.render::<Luma<u8>>().min_dimensions(512, 512).build();
+            let mut png = Cursor::new(Vec::new());
+
+            image.write_to(&mut png, ImageFormat::Png).map_err(| err | err.to_string())?;
+
+            Ok((png.into_inner(), "image/png"))
+        },
+        ConfigFormat::Svg => {
+            let image = code()?.render::<svg::Color>().min_dimensions(512, 512).build();
+
+            Ok((image.into_bytes(), "image/svg+xml"))
+        },
+        // Terminals mostly draw light text on a dark background, so the colours are swapped to scan.
+        ConfigFormat::Text => {
+            let image = code()?.render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build();
+
+            Ok((image.into_bytes(), "text/plain; charset=utf-8"))
+        }
+    }
+}
+
+fn error_reply(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(json_reply(&serde_json::json!({ "message": message, "type": "error" })), status))
+}
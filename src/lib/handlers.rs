@@ -8,8 +8,13 @@ use warp::{http::StatusCode};
 
 use super::client_connection;
 
-pub async fn ws_handler(ws: warp::ws::Ws, config: WireGuard, parameters: Option<QueryParameters>) -> WsResult<impl Reply> {
-    Ok(ws.on_upgrade(move |socket| client_connection(socket, config, parameters)))
+pub async fn ws_handler(ws: warp::ws::Ws, config: WireGuard, parameters: Option<QueryParameters>) -> WsResult<Box<dyn Reply>> {
+    // A draining node only finishes the sessions it already has.
+    if config.lock().await.draining {
+        return Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    Ok(Box::new(ws.on_upgrade(move |socket| client_connection(socket, config, parameters))))
 }
 
 #[derive(Serialize, Debug)]
@@ -0,0 +1,141 @@
+use crate::{types::Connection, wireguard::{WireGuard, WireGuardConfig}};
+use chrono::{Duration, Utc};
+use futures_timer::Delay;
+
+use super::close_query;
+
+// A connected client is idle once its peer has gone `idle_timeout_minutes` without a handshake.
+// Live peers re-handshake at least every two minutes (keepalive is 25s), so this only catches
+// clients which vanished without closing their WebSocket. Peers which never completed a handshake
+// are measured from when their slot was assigned.
+pub async fn idle_clients(configuration: &WireGuardConfig) -> Vec<String> {
+    let timeout = Duration::minutes(configuration.config.idle_timeout_minutes);
+    let now = Utc::now();
+
+    configuration.clients.lock().await.iter()
+        .filter_map(| (client_id, client) | match &client.connected {
+            Connection::Connected(host) => {
+                let connected_for = now - host.conn_time;
+
+                // A handshake from before the slot was assigned belongs to an earlier session.
+                let idle_for = match client.stats.as_ref().and_then(| stats | stats.handshake_age()) {
+                    Some(age) if age < connected_for => age,
+                    _ => connected_for
+                };
+
+                if idle_for >= timeout {
+                    Some(client_id.clone())
+                } else {
+                    None
+                }
+            },
+            Connection::Disconnected => None
+        })
+        .collect()
+}
+
+pub async fn idle_reaper(config: WireGuard) {
+    let timeout = config.lock().await.config.idle_timeout_minutes;
+
+    if timeout <= 0 {
+        println!("[idle]: Idle peer reaping disabled");
+        return;
+    }
+
+    loop {
+        // Task will run every *1m*
+        Delay::new(std::time::Duration::from_secs(60)).await;
+
+        let idle = idle_clients(&*config.lock().await).await;
+
+        for client_id in idle {
+            let configuration = config.lock().await;
+
+            // The client may have handshaked (or closed) since the list was taken.
+            if !idle_clients(&configuration).await.contains(&client_id) {
+                continue;
+            }
+
+            println!("[idle]: Closing session for {}, no handshake in {} minutes", client_id, timeout);
+
+            // Runs the regular close path, so usage is still recorded and the slot is freed.
+            close_query(&client_id, configuration).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Client;
+    use crate::wireguard::{MemoryBackend, WireGuardBackend};
+    use crate::wireguard::testing::{INTERFACE, client, connect, node};
+    use chrono::DateTime;
+
+    // A client connected `connected_for` ago, whose peer last handshaked `handshake` ago, if ever.
+    async fn connected(config: &mut WireGuardConfig, backend: &MemoryBackend, public_key: &str, connected_for: Duration, handshake: Option<Duration>) -> Client {
+        let mut client = connect(config, public_key);
+
+        if let Connection::Connected(host) = &mut client.connected {
+            host.conn_time = Utc::now() - connected_for;
+        }
+
+        config.add_peer(&client).await.unwrap();
+
+        let latest_handshake: Option<DateTime<Utc>> = handshake.map(| age | Utc::now() - age);
+        backend.set_latest_handshake(INTERFACE, public_key, latest_handshake).unwrap();
+
+        let stats = backend.dump(INTERFACE).unwrap().into_iter().find(| stats | stats.public_key == public_key).unwrap();
+        client.set_stats(stats);
+
+        client
+    }
+
+    async fn idle_after(config: &mut WireGuardConfig, client: Client) -> bool {
+        let public_key = client.public_key.clone();
+        config.clients.lock().await.insert(public_key.clone(), client);
+
+        idle_clients(config).await.contains(&public_key)
+    }
+
+    #[tokio::test]
+    async fn closes_peers_with_a_stale_handshake() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let client = connected(&mut config, &backend, "stale", Duration::hours(1), Some(Duration::minutes(20))).await;
+        assert!(idle_after(&mut config, client).await);
+    }
+
+    #[tokio::test]
+    async fn keeps_peers_with_a_fresh_handshake() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let client = connected(&mut config, &backend, "fresh", Duration::hours(1), Some(Duration::minutes(1))).await;
+        assert!(!idle_after(&mut config, client).await);
+    }
+
+    #[tokio::test]
+    async fn measures_peers_without_a_handshake_from_their_slot() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let client = connected(&mut config, &backend, "vanished", Duration::minutes(20), None).await;
+        assert!(idle_after(&mut config, client).await);
+
+        let client = connected(&mut config, &backend, "joining", Duration::minutes(1), None).await;
+        assert!(!idle_after(&mut config, client).await);
+
+        // A handshake older than the slot is one of an earlier session.
+        let client = connected(&mut config, &backend, "returning", Duration::minutes(1), Some(Duration::minutes(30))).await;
+        assert!(!idle_after(&mut config, client).await);
+    }
+
+    #[tokio::test]
+    async fn ignores_disconnected_clients() {
+        let (mut config, _) = node();
+
+        assert!(!idle_after(&mut config, client("closed", "author")).await);
+    }
+}
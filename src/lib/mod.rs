@@ -1,5 +1,13 @@
 mod ws;
 mod handlers;
+mod idle;
+mod shutdown;
+mod export;
+mod chain;
 
 pub use handlers::*;
-pub use ws::*;
\ No newline at end of file
+pub use ws::*;
+pub use idle::*;
+pub use shutdown::*;
+pub use export::*;
+pub use chain::*;
\ No newline at end of file
@@ -0,0 +1,65 @@
+use crate::{types::{Connection, ServerMessage, ShutdownNotice}, wireguard::WireGuard};
+use chrono::{Duration, Utc};
+use tokio::signal::unix::{signal, SignalKind};
+
+use super::close_query;
+
+// Resolves on SIGTERM (sent on every kubernetes rollout) or SIGINT, after marking the node as draining
+// so no further sessions are accepted. Given to warp so the listener is closed at the same time.
+pub async fn shutdown_signal(config: WireGuard) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => panic!("[err]: Unable to listen for SIGTERM. Reason: {}", err)
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => println!("[shutdown]: Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => println!("[shutdown]: Received SIGINT")
+    }
+
+    config.lock().await.draining = true;
+}
+
+// Closes every open session through `close_query`, so its usage is inserted and billed exactly as
+// if the client had closed it, then takes the interfaces down. Sessions which have not been closed
+// by `shutdown_grace_seconds` are abandoned, as the orchestrator will kill the node regardless.
+pub async fn drain(config: WireGuard) {
+    let grace = config.lock().await.config.shutdown_grace_seconds.max(0);
+    let deadline = Utc::now() + Duration::seconds(grace);
+
+    let notice = ServerMessage::Shutdown(ShutdownNotice {
+        deadline: deadline.to_rfc3339()
+    });
+
+    let connected: Vec<String> = {
+        let configuration = config.lock().await;
+        let clients = configuration.clients.lock().await;
+
+        clients.iter()
+            .filter(| (_, client) | matches!(client.connected, Connection::Connected(_)))
+            .map(| (client_id, client) | {
+                client.send_message(&notice);
+                client_id.clone()
+            })
+            .collect()
+    };
+
+    println!("[shutdown]: Closing {} sessions, deadline {}", connected.len(), deadline);
+
+    let closing = async {
+        for client_id in connected.iter() {
+            close_query(client_id, config.lock().await).await;
+        }
+    };
+
+    match tokio::time::timeout(std::time::Duration::from_secs(grace as u64), closing).await {
+        Ok(_) => println!("[shutdown]: Recorded usage of every session"),
+        Err(_) => println!("[err]: Shutdown deadline passed before every session was closed, remaining usage is lost")
+    }
+
+    if let Err(err) = config.lock().await.config_down().await {
+        println!("[err]: Unable to take down interfaces. Reason: {}", err);
+    }
+
+    println!("[shutdown]: Complete");
+}
@@ -1,6 +1,8 @@
-use crate::{Clients, types::{self, Query, QueryParameters, Client, Connection, Reservation, Slot}, wireguard::{WireGuard, WireGuardConfig}};
+use crate::{Clients, types::{self, Query, QueryParameters, Client, Connection, PresharedKey, Reservation, ServerMessage, Slot}, wireguard::{ExitSession, WireGuard, WireGuardConfig, generate_config_token, open_exit_session}};
+use super::hold_exit_session;
 use chrono::Utc;
 use futures::{FutureExt, StreamExt};
+use ipnet::IpNet;
 use tokio::sync::{mpsc, MutexGuard};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
@@ -81,7 +83,7 @@ pub async fn client_connection(ws: WebSocket, config: WireGuard, parameters: Opt
                     let clone_pk = pk.clone();
                     let configuration_reference = config.clone();
 
-                    tokio::spawn(async move {
+                    let tier_lookup = tokio::spawn(async move {
                         // Pool is cheap to clone - https://docs.rs/sqlx/latest/sqlx/struct.Pool.html
                         // In order to reduce connection times, we must hold locks on config for as little time as possible.
 
@@ -150,14 +152,33 @@ pub async fn client_connection(ws: WebSocket, config: WireGuard, parameters: Opt
                             }
                         };
 
-                        match configuration_reference.lock().await.clients.lock().await.get_mut(&clone_pk) {
+                        let mut configuration = configuration_reference.lock().await;
+                        // Held through its own reference, as moving the exit address needs the configuration mutably.
+                        let clients = configuration.clients.clone();
+                        let mut clients = clients.lock().await;
+
+                        match clients.get_mut(&clone_pk) {
                             Some(client) => {
+                                let previous = configuration.shaped_peer(client);
+
                                 client.set_tier(maximums);
                                 client.set_limit(limit);
+
+                                // A session still open from an earlier connection is reshaped, should its tier have changed since.
+                                configuration.reshape_peer(client, previous);
+
+                                // Likewise it moves to a dedicated exit address, should it have become PRO.
+                                if let Some(exit_address) = configuration.reassign_exit_address(client) {
+                                    client.send_message(&ServerMessage::ExitAddress(types::ExitAddressNotice {
+                                        exit_address: exit_address.to_string()
+                                    }));
+                                }
                             }
                             None => {}
                         }
                     });
+
+                    let mut tier_lookup = Some(tier_lookup);
                     
                     while let Some(result) = client_ws_rcv.next().await {
                         let msg = match result {
@@ -167,6 +188,13 @@ pub async fn client_connection(ws: WebSocket, config: WireGuard, parameters: Opt
                                 break;
                             }
                         };
+
+                        // Sessions are placed (and leased) on an interface by tier, so no query is handled before it is known.
+                        if let Some(lookup) = tier_lookup.take() {
+                            if let Err(err) = lookup.await {
+                                println!("[err]: Tier lookup for {} failed, it remains unassigned. Reason: {}", pk, err);
+                            }
+                        }
                 
                         client_msg(&pk, msg, &config).await;
                     }
@@ -205,7 +233,8 @@ pub async fn client_connection(ws: WebSocket, config: WireGuard, parameters: Opt
 pub async fn close_query(client_id: &str, mut configuration: MutexGuard<'_, WireGuardConfig>) {
     println!("[evt]: Closing connection: Start");
 
-    let mut locked = configuration.clients.lock().await;
+    let clients = configuration.clients.clone();
+    let mut locked = clients.lock().await;
 
     println!("[evt]: Closing connection: Obtained Client Lock");
     
@@ -218,11 +247,31 @@ pub async fn close_query(client_id: &str, mut configuration: MutexGuard<'_, Wire
                 }
                 Connection::Connected(connection) => {
                     println!("[evt]: Closing connection: Found connection to drop");
+
+                    // Removed while still connected, so its slot bound state (i.e. rate limits) can be found.
+                    match configuration.remove_peer(&client.clone()).await {
+                        Ok(_) => println!("[evt]: Closing connection: Removed Peer"),
+                        Err(err) => println!("[err]: Closing connection: Failed to remove peer, {}", err),
+                    }
+
+                    // Dropping the session with the exit node closes it there.
+                    if let Some(session) = configuration.detach_exit_session(&client.public_key) {
+                        session.close();
+                    }
+                    client.exit_node = None;
+                    configuration.release_ports(&client.public_key);
+                    configuration.release_exit_address(&client.clone());
+                    client.exit_address = None;
+                    client.allowed_ips.clear();
+                    // Revokes access to the sessions config.
+                    client.config_token = None;
                     
                     client.set_connectivity(Connection::Disconnected);
-                    configuration.remove_peer(&client.clone()).await;
-
-                    println!("[evt]: Closing connection: Removed Peer");
+                    // The preshared key is only good for this session.
+                    client.preshared_key = None;
+                    client.stats = None;
+                    // Noted on the Usage record, i.e. `throttle:1000`, so billing can tell the session went over.
+                    let overage = client.overage.take().map(| policy | policy.to_string());
 
                     let connection_usage = &client.get_usage().clone();
                     let con_time = &connection.conn_time.to_rfc3339().clone();
@@ -235,52 +284,57 @@ pub async fn close_query(client_id: &str, mut configuration: MutexGuard<'_, Wire
 
                     let author_id = client.author.clone();
 
-                    println!("[evt]: Closing connection: Creating Transaction");
-
-                    match configuration.pool.begin().await {
-                        Ok(mut transaction) => {
-                            match sqlx::query!("insert into Usage (id, userId, serverId, up, down, connStart, connEnd) values (?, ?, ?, ?, ?, ?, ?)", session_id, author_id, configuration.config.name, up, down, con_time, now)
-                                .execute(&mut transaction)
-                                .await {
-                                    Ok(_returned_information) => {
-                                        println!("[evt]: Closing connection: Committing Transaction");
-
-                                        match transaction.commit().await {
-                                            Ok(r2) => {
-                                                println!("[sqlx]: Usage Log Transaction Result: {:?}", r2);
-
-                                                match reqwest::Client::new()
-                                                    .post("https://reseda.app/api/billing/usage-reccord")
-                                                    .json(&serde_json::json!({
-                                                        "sessionId": session_id,
-                                                    }))
-                                                    .send()
-                                                    .await {
-                                                        Ok(r) => {
-                                                            match r.text().await {
-                                                                Ok(_) => {
-                                                                    // Success!
-                                                                    // Here the Reseda API has published the usage-record of the service to stripe, thus meaning that the users logging has been billed to them.
-                                                                    // Notably, if the user is under a FREE or SUPPORTER tier, they will not be charged anything, as the API will return a ERROR:400, indicating failure to recognise a valid stripe subscription to thier billing profile.
-                                                                },
-                                                                Err(error) => println!("[api.reseda]: Failed to record usage-record with reseda, API returned: {:?}", error),
-                                                            };
-                                                        },
-                                                        Err(error) => {
-                                                            println!("[api.reseda]: Failed to record usage-record with reseda, API returned: {:?}", error)
-                                                        },
-                                                    }
-                                            },
-                                            Err(error) => println!("[sqlx]: Transaction Commit Error: {:?}", error),
-                                        }
-                                    },
-                                    Err(error) => println!("[sqlx]: Transaction Commit Error: {:?}", error),
-                                };
-                        },
-                        Err(err) => {
-                            println!("[err]: Unable to perform request, user will remain unassigned. Reason: {}", err);
-                        }
-                    };
+                    // Sessions relayed for an entry node are recorded (and billed) there, against the clients own account.
+                    if configuration.config.chain.is_entry(&author_id) {
+                        println!("[chain]: Closing connection: Relayed for entry {}, its usage is recorded there", author_id);
+                    } else {
+                        println!("[evt]: Closing connection: Creating Transaction");
+
+                        match configuration.pool.begin().await {
+                            Ok(mut transaction) => {
+                                match sqlx::query!("insert into Usage (id, userId, serverId, up, down, connStart, connEnd, overage) values (?, ?, ?, ?, ?, ?, ?, ?)", session_id, author_id, configuration.config.name, up, down, con_time, now, overage)
+                                    .execute(&mut transaction)
+                                    .await {
+                                        Ok(_returned_information) => {
+                                            println!("[evt]: Closing connection: Committing Transaction");
+
+                                            match transaction.commit().await {
+                                                Ok(r2) => {
+                                                    println!("[sqlx]: Usage Log Transaction Result: {:?}", r2);
+
+                                                    match reqwest::Client::new()
+                                                        .post("https://reseda.app/api/billing/usage-reccord")
+                                                        .json(&serde_json::json!({
+                                                            "sessionId": session_id,
+                                                        }))
+                                                        .send()
+                                                        .await {
+                                                            Ok(r) => {
+                                                                match r.text().await {
+                                                                    Ok(_) => {
+                                                                        // Success!
+                                                                        // Here the Reseda API has published the usage-record of the service to stripe, thus meaning that the users logging has been billed to them.
+                                                                        // Notably, if the user is under a FREE or SUPPORTER tier, they will not be charged anything, as the API will return a ERROR:400, indicating failure to recognise a valid stripe subscription to thier billing profile.
+                                                                    },
+                                                                    Err(error) => println!("[api.reseda]: Failed to record usage-record with reseda, API returned: {:?}", error),
+                                                                };
+                                                            },
+                                                            Err(error) => {
+                                                                println!("[api.reseda]: Failed to record usage-record with reseda, API returned: {:?}", error)
+                                                            },
+                                                        }
+                                                },
+                                                Err(error) => println!("[sqlx]: Transaction Commit Error: {:?}", error),
+                                            }
+                                        },
+                                        Err(error) => println!("[sqlx]: Transaction Commit Error: {:?}", error),
+                                    };
+                            },
+                            Err(err) => {
+                                println!("[err]: Unable to perform request, user will remain unassigned. Reason: {}", err);
+                            }
+                        };
+                    }
 
                     Slot::Open(connection.clone())
                 }
@@ -294,8 +348,8 @@ pub async fn close_query(client_id: &str, mut configuration: MutexGuard<'_, Wire
     drop(locked);
     match connection_to_drop {
         Slot::Open(drop) => {
-            println!("[reserver]: Freeing up now unused slot; {:?}", drop);
-            configuration.free_slot(&drop);
+            println!("[reserver]: Releasing now unused slot; {:?}", drop);
+            configuration.release_slot(client_id, &drop);
         },
         Slot::Prospective => println!("[reserver]: Error, Could not drop"),
     }
@@ -316,50 +370,159 @@ pub async fn close_query(client_id: &str, mut configuration: MutexGuard<'_, Wire
     }
 }
 
-pub async fn open_query(client_id: &str, mut configuration: MutexGuard<'_, WireGuardConfig>) {
-    let slot = configuration.find_open_slot();
-    println!("[reserver]: Found slot: {:?}", slot);
-
-    let reserved_slot = match slot {
-        types::Slot::Open(open_slot) => configuration.reserve_slot(open_slot),
-        types::Slot::Prospective => Reservation::Imissable,
+// `exit` is the session opened with the exit node beforehand for a chained client, see `open_exit_session`.
+pub async fn open_query(client_id: &str, config: &WireGuard, mut configuration: MutexGuard<'_, WireGuardConfig>, requested: Option<String>, routes: Vec<IpNet>, exit: Option<ExitSession>) {
+    let (tier, author) = match configuration.clients.lock().await.get(client_id) {
+        Some(client) => (client.tier(), client.author.clone()),
+        None => ("UNASSIGNED", String::new())
     };
+
+    // A reconnecting client is given the slot it had before, while its lease holds.
+    let reserved_slot = configuration.take_slot(client_id, &author, tier, requested.as_deref());
     println!("[reserver]: Reserved Slot: {:?}", reserved_slot);
 
     match reserved_slot {
         Reservation::Held(valid_slot) => {
-            let mut lock = configuration.clients.lock().await;
+            // Held through its own reference, as holding the session with an exit node needs the configuration mutably.
+            let clients = configuration.clients.clone();
+            let mut lock = clients.lock().await;
             let client = lock.get_mut(client_id);
 
-            match client {
+            let assigned = match client {
                 Some(v) => {
                     let clone = &valid_slot.clone();
 
-                    v.set_connectivity(Connection::Connected(valid_slot));
-                    configuration.add_peer(v).await;
+                    v.set_connectivity(Connection::Connected(valid_slot.clone()));
+
+                    if configuration.config.preshared_keys {
+                        v.preshared_key = Some(PresharedKey::generate());
+                    }
 
-                    let a = &clone.a.clone();
-                    let b = &clone.b.clone();
+                    let created = match configuration.add_peer(v).await {
+                        Ok(_) => Ok(()),
+                        Err(err) => {
+                            println!("[err]: Unable to create peer {:?} on slot {:?}, {}", v.public_key, clone, err);
+                            Err("Unable to create peer.".to_string())
+                        }
+                    };
 
-                    let message = format!(
-                        "{{ \"message\": {{ \"server_public_key\": \"{}\", \"endpoint\": \"{}:{}\", \"subdomain\": \"{}.{}\" }}, \"type\": \"message\" }}", 
-                        configuration.keys.public_key.trim(), 
-                        configuration.config.address, 
-                        configuration.config.listen_port.trim(),
-                        &a, &b
-                    );
-                    
-                    if let Some(sender) = &v.sender {
-                        let _ = sender.send(Ok(Message::text(message)));
+                    match created {
+                        Ok(_) => {
+                            // The session with the exit node is held from here on, until this one closes.
+                            let exit = exit.map(| session | {
+                                let (route, endpoint) = (session.route.clone(), session.endpoint);
+                                let task = tokio::spawn(hold_exit_session(config.clone(), client_id.to_string(), session.request, session.socket));
+
+                                configuration.attach_exit_session(client_id, route.clone(), endpoint, task);
+                                v.exit_node = Some(route.name.clone());
+
+                                println!("[chain]: Chained {:?} through exit {} at {}", v.public_key, route.name, endpoint);
+                                (route, endpoint)
+                            });
+
+                            // Chained sessions leave through their exit node instead. Failing to assign an address
+                            // is not fatal, the session is masqueraded behind the nodes address as before.
+                            if exit.is_none() {
+                                match configuration.assign_exit_address(v) {
+                                    Ok(exit_address) => v.exit_address = exit_address,
+                                    Err(err) => println!("[err]: Unable to assign an exit address to {:?}, {}", v.public_key, err)
+                                }
+                            }
+
+                            let a = &clone.a.clone();
+                            let b = &clone.b.clone();
+
+                            let interface = configuration.interface(&clone.interface);
+                            let listen_port = interface.map(| interface | interface.listen_port).unwrap_or_default();
+                            let address = serde_json::json!(interface.map(| interface | interface.host_ipv4(clone).to_string()));
+
+                            // `null` when the interface is not dual-stack.
+                            let address_v6 = serde_json::json!(interface.and_then(| interface | interface.host_ipv6(clone)).map(| address | address.to_string()));
+
+                            // `null` unless preshared keys are enabled on this node.
+                            let preshared_key = serde_json::json!(v.preshared_key.as_ref().map(| key | key.as_str()));
+
+                            // What the client should set as AllowedIPs, its requested routes and the interfaces own networks. Chained
+                            // sessions route only the exit node through this one, their routes apply to the inner peer.
+                            v.allowed_ips = match &exit {
+                                Some((_, endpoint)) => configuration.chained_allowed_ips(v, *endpoint),
+                                None => types::with_tunnel(&routes, &interface.map(| interface | interface.networks()).unwrap_or_default())
+                            };
+
+                            let allowed_ips = serde_json::json!(v.allowed_ips.iter()
+                                .map(| network | network.to_string())
+                                .collect::<Vec<String>>());
+
+                            // Port 53 of each address, the built-in resolver unless it is disabled.
+                            let dns = serde_json::json!(interface.map(| interface | configuration.client_dns(interface)).unwrap_or_default());
+
+                            // The inner peer with the exit node, as it answered for the client. `null` unless chained.
+                            let exit_node = serde_json::json!(exit.as_ref().map(| (route, _) | route));
+
+                            // The public address the sessions traffic appears from, `null` when chained.
+                            let exit_address = serde_json::json!(match (&exit, v.exit_address) {
+                                (Some(_), _) => None,
+                                (None, Some(exit_address)) => Some(exit_address.to_string()),
+                                (None, None) => Some(configuration.config.address.clone())
+                            });
+
+                            // The complete wg-quick config less the PrivateKey, which only the client holds. The token fetches
+                            // it again, i.e. as a QR code, from `/config`.
+                            v.config_token = Some(generate_config_token());
+
+                            let client_config = serde_json::json!(configuration.client_config(v, None).map(| client_config | client_config.to_string()));
+                            let config_token = serde_json::json!(v.config_token);
+
+                            let message = format!(
+                                "{{ \"message\": {{ \"server_public_key\": \"{}\", \"endpoint\": \"{}:{}\", \"subdomain\": \"{}.{}\", \"address\": {}, \"address_v6\": {}, \"preshared_key\": {}, \"exit_node\": {}, \"allowed_ips\": {}, \"dns\": {}, \"dns_protected\": {}, \"exit_address\": {}, \"config\": {}, \"config_token\": {} }}, \"type\": \"message\" }}", 
+                                configuration.keys.public_key.trim(), 
+                                configuration.config.address, 
+                                listen_port,
+                                &a, &b,
+                                address,
+                                address_v6,
+                                preshared_key,
+                                exit_node,
+                                allowed_ips,
+                                dns,
+                                // Every lookup goes through the resolver above, whatever the client or its apps are configured to use.
+                                configuration.firewall.dns_leak_protection,
+                                exit_address,
+                                client_config,
+                                config_token
+                            );
+                            
+                            if let Some(sender) = &v.sender {
+                                let _ = sender.send(Ok(Message::text(message)));
+                            }
+
+                            // The session missed the announcement of a rotation which began before it opened.
+                            if let Some(notice) = configuration.rekey_notice() {
+                                v.send_message(&notice);
+                            }
+                 
+                            println!("[evt]: Success, Created Peer {:?} on slot {:?}", v.public_key, v.connected);
+                            true
+                        }
+                        Err(reason) => {
+                            v.set_connectivity(Connection::Disconnected);
+                            v.preshared_key = None;
+
+                            if let Some(sender) = &v.sender {
+                                let _ = sender.send(Ok(Message::text(serde_json::json!({ "message": reason, "type": "error" }).to_string())));
+                            }
+
+                            false
+                        }
                     }
-         
-                    println!("[evt]: Success, Created Peer {:?} on slot {:?}", v.public_key, v.connected);
                 }
-                None => {
-                    drop(lock);
-                    // Found and reserved slot, however was not able to get lock on client, so we free the slot as it is not assigned to any user.
-                    configuration.free_slot(&valid_slot);
-                },
+                None => false
+            };
+
+            if !assigned {
+                drop(lock);
+                // Found and reserved slot, however was not able to assign it to the user, so we free the slot as it is not held by anyone.
+                configuration.drop_lease(client_id, &valid_slot);
             }
         }
         Reservation::Imissable => {
@@ -388,15 +551,95 @@ async fn client_msg(client_id: &str, msg: Message, config: &WireGuard) {
         Query::Open => {
             let configuration = config.lock().await;
 
-            open_query(client_id, configuration).await;
+            if configuration.draining {
+                drop(configuration);
+                return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": "Server is shutting down.", "type": "error" }).to_string()).await;
+            }
+
+            let routes = match configuration.config.routes.routes(&json.routes) {
+                Ok(routes) => routes,
+                Err(err) => {
+                    drop(configuration);
+                    return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": format!("Invalid routes: {}.", err), "type": "error" }).to_string()).await;
+                }
+            };
+
+            // Checked before a slot is reserved, so an unknown exit node never creates a peer.
+            let request = match &json.exit_node {
+                Some(exit) => match configuration.exit_request(exit, json.exit_public_key.as_deref()) {
+                    Ok(request) => Some(request),
+                    Err(err) => {
+                        drop(configuration);
+                        return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": format!("Unable to route through exit node: {}.", err), "type": "error" }).to_string()).await;
+                    }
+                },
+                None => None
+            };
+
+            // Opening a session with the exit node waits on it for up to 10s, so no lock is held meanwhile.
+            let (configuration, exit) = match request {
+                Some(request) => {
+                    drop(configuration);
+
+                    let session = match open_exit_session(request).await {
+                        Ok(session) => session,
+                        Err(err) => {
+                            println!("[err]: Unable to open a session with exit node {:?}, {}", json.exit_node, err);
+                            return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": format!("Unable to route through exit node: {}.", err), "type": "error" }).to_string()).await;
+                        }
+                    };
+
+                    let configuration = config.lock().await;
+
+                    // The node may have begun shutting down in the meantime.
+                    if configuration.draining {
+                        drop(configuration);
+                        return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": "Server is shutting down.", "type": "error" }).to_string()).await;
+                    }
+
+                    (configuration, Some(session))
+                },
+                None => (configuration, None)
+            };
+
+            open_query(client_id, config, configuration, json.interface, routes, exit).await;
         },
         Query::Close => {
             let configuration = config.lock().await;
 
             close_query(client_id, configuration).await;
         },
+        Query::Rekey => {
+            let acknowledged = config.lock().await.acknowledge_rotation(client_id);
+
+            let message = match acknowledged {
+                true => "Acknowledged server key rotation.",
+                false => "No server key rotation is pending."
+            };
+
+            return return_to_sender(&config.lock().await.clients, client_id, format!("{{ \"message\": \"{}\", \"type\": \"message\" }}", message)).await;
+        },
+        Query::PortForward => {
+            let mut configuration = config.lock().await;
+
+            let client = match configuration.clients.lock().await.get(client_id) {
+                Some(client) => client.clone(),
+                None => return
+            };
+
+            match configuration.forward_port(&client) {
+                Ok(forward) => {
+                    client.send_message(&ServerMessage::PortForward(forward));
+                },
+                Err(err) => {
+                    println!("[err]: Unable to forward a port to {}, {}", client.public_key, err);
+                    drop(configuration);
+                    return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": format!("Unable to forward a port: {}.", err), "type": "error" }).to_string()).await;
+                }
+            }
+        },
         _ => {
-            return return_to_sender(&config.lock().await.clients, client_id, format!("{{ \"message\": \"Unknown query_type, expected one of open, close, resume.\", \"type\": \"error\" }}")).await;
+            return return_to_sender(&config.lock().await.clients, client_id, serde_json::json!({ "message": "Unknown query_type, expected one of open, close, rekey, port_forward.", "type": "error" }).to_string()).await;
         }
     }
 }
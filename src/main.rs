@@ -1,12 +1,14 @@
 use crate::lib::close_query;
-use crate::types::{Clients, Connection, QueryParameters};
-use crate::wireguard::{WireGuard, WireGuardConfig};
+use crate::types::{Client, Clients, Connection, OverageNotice, OveragePolicy, PeerStats, QueryParameters, ServerMessage};
+use crate::wireguard::{WireGuard, WireGuardConfig, reconciler, rotation_scheduler};
 use futures_timer::Delay;
-use std::{convert::Infallible, process::Command, sync::Arc, time::Duration};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use warp::ws::Message;
 use warp::{Filter, Rejection};
 
+mod dns;
+mod firewall;
 mod lib;
 mod types;
 mod wireguard;
@@ -41,139 +43,176 @@ async fn main() {
         .and(with_config(config.clone()))
         .and_then(lib::health_status);
 
+    // POST /config, a sessions wg-quick config (or a QR code of it) for third-party WireGuard apps.
+    let config_route = warp::path("config")
+        .and(warp::post())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_config(config.clone()))
+        .and_then(lib::client_config_handler);
+
     let routes = ws_route
         .or(echo_route)
         .or(health_route)
+        .or(config_route)
         .with(warp::cors().allow_any_origin());
 
+    let shutdown_config = config.clone();
+
+    tokio::spawn(rotation_scheduler(config.clone()));
+    tokio::spawn(reconciler(config.clone()));
+    tokio::spawn(lib::idle_reaper(config.clone()));
+    tokio::spawn(dns::dns_server(config.clone()));
+
     tokio::spawn(async move {
         loop {
             // Task will run ever *1s*
-            match Command::new("wg")
-                .args(["show", "reseda", "transfer"])
-                .output()
-            {
-                Ok(output) => {
-                    match String::from_utf8(output.stdout) {
-                        Ok(mut string) => {
-                            string = string.trim().to_string();
-                            for line in string.split("\n").into_iter() {
-                                if line == "" {
-                                    break;
-                                };
-
-                                let split_vector = line.trim().split("\t");
-                                let vec: Vec<&str> = split_vector.collect();
-
-                                let mut config_lock = config.lock().await;
-
-                                let config_clone = config_lock.clone();
-                                let mut clients_lock = config_clone.clients.lock().await;
-
-                                let client = match clients_lock.get_mut(&vec[0].to_string()) {
-                                    Some(client) => client,
-                                    None => {
-                                        println!("[err]: No user matched for this!");
-                                        break;
-                                    }
-                                };
+            let (backend, interfaces) = {
+                let lock = config.lock().await;
+                (lock.backend.clone(), lock.config.interfaces.iter().map(| interface | interface.name.clone()).collect::<Vec<String>>())
+            };
+
+            for interface in interfaces.iter() {
+                match backend.dump(interface) {
+                    Ok(peers) => {
+                        for stats in peers.iter() {
+                            let mut config_lock = config.lock().await;
+
+                            let config_clone = config_lock.clone();
+                            let mut clients_lock = config_clone.clients.lock().await;
+
+                            let client = match clients_lock.get_mut(&stats.public_key) {
+                                Some(client) => client,
+                                None => {
+                                    // Orphaned peers are removed by the reconciler, the remaining peers still need accounting.
+                                    println!("[err]: No user matched for peer {}", stats.public_key);
+                                    continue;
+                                }
+                            };
+
+                            let up = stats.rx as i128;
+                            let down = stats.tx as i128;
+
+                            client.set_stats(stats.clone());
+
+                            // Sessions relayed for an entry node are held to their allowance there.
+                            let usage_query = match config_lock.config.chain.is_entry(&client.author) {
+                                true => Ok(()),
+                                false => client.set_usage(&up, &down)
+                            };
+
+                            match usage_query {
+                                Ok(_) => {
+                                    // If a usage could be set...
+                                    send_usage_update(client, stats, up, down);
+                                },
+                                Err(_) => {
+                                    // If usage could not be sent...
+                                    println!(
+                                        "[warn]: Exceeded maximum usage, given {}, had {}/{}",
+                                        client.maximums.to_value(client.limit),
+                                        up,
+                                        down
+                                    );
+
+                                    let policy = config_lock.config.overage_policy(client.tier());
+
+                                    // Sessions of tiers which degrade instead of disconnecting carry on, the policy is applied once.
+                                    if policy != OveragePolicy::Disconnect && matches!(client.connected, Connection::Connected(_)) {
+                                        if client.overage.is_none() {
+                                            let previous = config_lock.shaped_peer(client);
+                                            client.overage = Some(policy);
+
+                                            if let OveragePolicy::Throttle { .. } = policy {
+                                                config_lock.reshape_peer(client, previous);
+                                            }
 
-                                let up = vec[1].parse::<i128>().unwrap();
-                                let down = vec[2].parse::<i128>().unwrap();
+                                            client.send_message(&ServerMessage::Overage(OverageNotice {
+                                                policy,
+                                                up,
+                                                down,
+                                                allowance: client.maximums.to_value(client.limit)
+                                            }));
 
-                                let usage_query = client.set_usage(&up, &down);
+                                            println!("[usage]: User {} exceeded their allowance, applying {}", client.public_key, policy);
+                                        }
 
-                                match usage_query {
-                                    Ok(_) => {
-                                        // If a usage could be set...
-                                        let message = format!("{{ \"message\": {{ \"up\": {}, \"down\": {} }}, \"type\": \"update\"}}", &up, &down);
+                                        send_usage_update(client, stats, up, down);
+                                        continue;
+                                    }
 
-                                        if let Some(sender) = &client.sender {
-                                            match sender.send(Ok(Message::text(message))) {
-                                                Ok(_) => {
-                                                    println!("[usage]: User {} is given {}, has used up::{}, down::{}", client.public_key, client.maximums.to_value(client.limit), up, down);
-                                                }
-                                                Err(e) => {
-                                                    println!("[err]: Failed to send message: \'INVALID_SENDER\', reason: {}", e)
-                                                }
-                                            }
+                                    let conn = client.connected.clone();
+
+                                    if conn == Connection::Disconnected {
+                                        println!("[err]: Something went wrong, attempted to directly remove user for exceeding limits who is not connected...");
+                                        Delay::new(Duration::from_millis(1000)).await;
+                                        if let Err(err) = config_lock.remove_peer(&client).await {
+                                            println!("[err]: Failed to remove peer {}, {}", client.public_key, err);
                                         }
-                                    },
-                                    Err(_) => {
-                                        // If usage could not be sent...
-                                        println!(
-                                            "[warn]: Exceeded maximum usage, given {}, had {}/{}",
-                                            client.maximums.to_value(client.limit),
-                                            up,
-                                            down
-                                        );
 
-                                        let conn = client.connected.clone();
+                                        let public_key = &client.public_key.clone();
 
-                                        if conn == Connection::Disconnected {
-                                            println!("[err]: Something went wrong, attempted to directly remove user for exceeding limits who is not connected...");
-                                            Delay::new(Duration::from_millis(1000)).await;
-                                            config_lock.remove_peer(&client).await;
+                                        drop(client);
+                                        drop(clients_lock);
 
-                                            let public_key = &client.public_key.clone();
+                                        println!("[evt]: Closing Service for user, config is arc-locked for this process.");
 
-                                            drop(client);
-                                            drop(clients_lock);
+                                        close_query(&public_key, config_lock).await;
 
-                                            println!("[evt]: Closing Service for user, config is arc-locked for this process.");
+                                        println!("[evt]: Closed Service for user, preparing to unlock config.");
 
-                                            close_query(&public_key, config_lock).await;
+                                        break;
+                                    }
 
-                                            println!("[evt]: Closed Service for user, preparing to unlock config.");
+                                    if let Connection::Connected(_val) = conn {
+                                        client.send_message(&ServerMessage::Overage(OverageNotice {
+                                            policy: OveragePolicy::Disconnect,
+                                            up,
+                                            down,
+                                            allowance: client.maximums.to_value(client.limit)
+                                        }));
 
-                                            break;
-                                        }
+                                        // Message: UserDisConnection-ExceededUsage, kept for clients which predate the overage message.
+                                        let message = format!(
+                                            "{{ \"message\": \"UDC-EU\", \"type\": \"error\"}}"
+                                        );
 
-                                        if let Connection::Connected(_val) = conn {
-                                            // Message: UserDisConnection-ExceededUsage
-                                            let message = format!(
-                                                "{{ \"message\": \"UDC-EU\", \"type\": \"error\"}}"
-                                            );
-
-                                            // Inform user of upcoming disconnection.
-                                            if let Some(sender) = &client.sender {
-                                                match sender.send(Ok(Message::text(message))) {
-                                                    Ok(_) => {
-                                                        println!("[messaging]: User exceeded usage and was send a disconnection warning.");
-                                                    }
-                                                    Err(e) => {
-                                                        println!("[err]: Failed to send message: \'INVALID_SENDER\', reason: {}", e)
-                                                    }
+                                        // Inform user of upcoming disconnection.
+                                        if let Some(sender) = &client.sender {
+                                            match sender.send(Ok(Message::text(message))) {
+                                                Ok(_) => {
+                                                    println!("[messaging]: User exceeded usage and was send a disconnection warning.");
+                                                }
+                                                Err(e) => {
+                                                    println!("[err]: Failed to send message: \'INVALID_SENDER\', reason: {}", e)
                                                 }
-                                            };
+                                            }
+                                        };
 
-                                            // Wait 200ms, to allow for throughput from buffer to leave and inform before pulling  (non-thread-blocking wait)
-                                            Delay::new(Duration::from_millis(200)).await;
+                                        // Wait 200ms, to allow for throughput from buffer to leave and inform before pulling  (non-thread-blocking wait)
+                                        Delay::new(Duration::from_millis(200)).await;
 
-                                            let public_key = &client.public_key.clone();
+                                        let public_key = &client.public_key.clone();
 
-                                            drop(client);
-                                            drop(clients_lock);
+                                        drop(client);
+                                        drop(clients_lock);
 
-                                            println!("[evt]: Closing Service for user, config is arc-locked for this process.");
+                                        println!("[evt]: Closing Service for user, config is arc-locked for this process.");
 
-                                            close_query(&public_key, config_lock).await;
+                                        close_query(&public_key, config_lock).await;
 
-                                            println!("[evt]: Closed Service for user, preparing to unlock config.");
-                                        };
-                                    },
-                                }
+                                        println!("[evt]: Closed Service for user, preparing to unlock config.");
+                                    };
+                                },
                             }
-
-                            println!("[evt]: Configuration unlocked.");
-                        }
-                        Err(err) => {
-                            println!("[err]: Parsing UTF8: {}", err)
                         }
+
+                        println!("[evt]: Configuration unlocked.");
+                    }
+                    Err(err) => {
+                        println!("[err]: Failed to read peers from {} interface, {}", interface, err);
                     }
-                }
-                Err(err) => {
-                    println!("[err]: Failed to bring up reseda server, {:?}", err);
                 }
             }
 
@@ -182,12 +221,36 @@ async fn main() {
         }
     });
 
-    warp::serve(routes)
+    let (_, server) = warp::serve(routes)
         .tls()
         .cert_path("cert.pem")
         .key_path("key.pem")
-        .run(([0, 0, 0, 0], 443))
-        .await;
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], 443), lib::shutdown_signal(shutdown_config.clone()));
+
+    server.await;
+
+    // The listener is closed, open sessions are still to be recorded.
+    lib::drain(shutdown_config).await;
+}
+
+fn send_usage_update(client: &Client, stats: &PeerStats, up: i128, down: i128) {
+    let message = format!(
+        "{{ \"message\": {{ \"up\": {}, \"down\": {}, \"latest_handshake\": {}, \"endpoint\": {} }}, \"type\": \"update\"}}",
+        &up, &down,
+        serde_json::json!(stats.latest_handshake.map(| handshake | handshake.to_rfc3339())),
+        serde_json::json!(stats.endpoint.map(| endpoint | endpoint.to_string()))
+    );
+
+    if let Some(sender) = &client.sender {
+        match sender.send(Ok(Message::text(message))) {
+            Ok(_) => {
+                println!("[usage]: User {} is given {}, has used up::{}, down::{}", client.public_key, client.maximums.to_value(client.limit), up, down);
+            }
+            Err(e) => {
+                println!("[err]: Failed to send message: \'INVALID_SENDER\', reason: {}", e)
+            }
+        }
+    }
 }
 
 fn with_config(
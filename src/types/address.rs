@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv6Addr;
+
+// A unique-local (fc00::/7) IPv6 prefix which peers are addressed out of. Every IPv4 host `x.y.a.b`
+// is paired with `<prefix>::a:b` (the last 16 bits hold `a` and `b`), so the two never drift apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Ipv6Prefix {
+    pub network: Ipv6Addr,
+    pub length: u8
+}
+
+impl Ipv6Prefix {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (network, length) = match value.trim().split_once('/') {
+            Some((network, length)) => (network, length),
+            None => return Err(format!("'{}' is missing a prefix length", value))
+        };
+
+        let network = network.parse::<Ipv6Addr>()
+            .map_err(| err | format!("'{}' is not an IPv6 address, {}", network, err))?;
+        let length = length.parse::<u8>()
+            .map_err(| err | format!("'{}' is not a prefix length, {}", length, err))?;
+
+        // The last two segments are reserved for the interface index and host.
+        if length > 96 {
+            return Err(format!("prefix /{} leaves no room for hosts, use /96 or shorter", length));
+        }
+
+        if network.segments()[0] & 0xfe00 != 0xfc00 {
+            return Err(format!("{} is not a unique-local (fc00::/7) address", network));
+        }
+
+        Ok(Ipv6Prefix { network, length })
+    }
+
+    // Distinct /112 subnet for the n-th interface, placed in the second to last segment,
+    // so interfaces never route each others peers.
+    pub fn subnet(&self, index: u16) -> Ipv6Prefix {
+        let mut segments = self.network.segments();
+        segments[6] = index;
+        segments[7] = 0;
+
+        Ipv6Prefix {
+            network: Ipv6Addr::from(segments),
+            length: 112
+        }
+    }
+
+    pub fn host(&self, a: u8, b: u8) -> Ipv6Addr {
+        let mut segments = self.network.segments();
+        segments[7] = (a as u16) << 8 | b as u16;
+
+        Ipv6Addr::from(segments)
+    }
+}
+
+impl fmt::Display for Ipv6Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.length)
+    }
+}
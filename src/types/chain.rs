@@ -0,0 +1,57 @@
+use config::Config;
+use serde::{Deserialize, Serialize};
+
+// Another Reseda node clients of this node may exit through.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExitNode {
+    // What clients ask for in the `open` query.
+    pub name: String,
+    // The exit nodes WebSocket endpoint, i.e. wss://exit.reseda.app/ws
+    pub url: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainConfig {
+    // Account the entry node opens sessions on exit nodes under, None disables chaining.
+    // Usage of chained clients is recorded on the entry node, against the clients own accounts.
+    pub author: Option<String>,
+    pub exit_nodes: Vec<ExitNode>,
+    // Accounts entry nodes open sessions on this node under. Those sessions are accounted on their entry
+    // node, so here they are neither held to a quota nor written to Usage.
+    pub entry_authors: Vec<String>
+}
+
+impl ChainConfig {
+    pub fn from_settings(settings: &Config) -> Self {
+        ChainConfig {
+            author: settings.get_string("chain_author").ok(),
+            exit_nodes: settings.get::<Vec<ExitNode>>("chain_exit_nodes").unwrap_or_default(),
+            entry_authors: settings.get::<Vec<String>>("chain_entry_authors").unwrap_or_default()
+        }
+    }
+
+    pub fn exit_node(&self, name: &str) -> Option<&ExitNode> {
+        self.exit_nodes.iter().find(| exit | exit.name == name)
+    }
+
+    // Whether sessions of `author` were opened by an entry node on behalf of its own clients.
+    pub fn is_entry(&self, author: &str) -> bool {
+        self.entry_authors.iter().any(| entry | entry == author)
+    }
+}
+
+// The inner peer of a chained client, as the exit node answered the `open` query made on its behalf.
+// Only the client holds the private key, so the entry node relays its traffic without being able to read it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExitRoute {
+    // Name of the exit node, set by the entry node.
+    #[serde(skip_deserializing)]
+    pub name: String,
+    pub server_public_key: String,
+    pub endpoint: String,
+    pub address: String,
+    pub address_v6: Option<String>,
+    pub preshared_key: Option<String>,
+    #[serde(default)]
+    pub dns: Vec<String>
+}
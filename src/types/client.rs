@@ -1,9 +1,10 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
 use chrono::{Utc, DateTime};
+use ipnet::IpNet;
 use tokio::sync::{mpsc, Mutex};
 use warp::ws::Message;
 
-use super::Usage;
+use super::{Usage, PeerStats, PresharedKey, OveragePolicy, ServerMessage, is_valid_key};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Maximums {
@@ -15,6 +16,51 @@ pub enum Maximums {
 } 
 
 impl Maximums {
+    // The tier name as stored in `Account.tier`.
+    pub fn tier(&self) -> &'static str {
+        match self {
+            Self::Free(..) => "FREE",
+            Self::Supporter(..) => "SUPPORTER",
+            Self::Basic(..) => "BASIC",
+            Self::Pro(..) => "PRO",
+            Self::Unassigned => "UNASSIGNED"
+        }
+    }
+
+    // Rate each of the clients directions is shaped to, None is unshaped.
+    pub fn rate_limit_kbit(&self) -> Option<u32> {
+        match self {
+            // 10 Mbit/s
+            Self::Free(..) | Self::Unassigned => Some(10_000),
+            // 50 Mbit/s
+            Self::Supporter(..) => Some(50_000),
+            // 100 Mbit/s
+            Self::Basic(..) => Some(100_000),
+            Self::Pro(..) => None
+        }
+    }
+
+    // Ports a session may have forwarded at once.
+    pub fn port_forwards(&self) -> usize {
+        match self {
+            Self::Free(..) | Self::Unassigned => 0,
+            Self::Supporter(..) => 1,
+            Self::Basic(..) => 3,
+            Self::Pro(..) => 10
+        }
+    }
+
+    // Every distinct shaped rate, so a firewall can prepare for each of them up front.
+    pub fn rate_limits() -> Vec<u32> {
+        let mut limits: Vec<u32> = [Self::Free(0, 0), Self::Supporter(0, 0), Self::Basic(0, 0), Self::Pro(0, 0), Self::Unassigned].iter()
+            .filter_map(| tier | tier.rate_limit_kbit())
+            .collect();
+
+        limits.sort();
+        limits.dedup();
+        limits
+    }
+
     pub fn to_value(&self, max_val: i128) -> i128 {
         match self {
             // 5GB
@@ -57,6 +103,8 @@ impl Maximums {
 // By choosing integers with the proper bounds, we cannot go out of bounds of the IP scope.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Host {
+    // Name of the interface this slot belongs to.
+    pub interface: String,
     pub a: u8,
     pub b: u8,
     pub conn_time: DateTime<Utc>
@@ -95,6 +143,20 @@ pub struct Client {
     pub maximums: Maximums,
     pub limit: i128,
     pub connected: Connection,
+    // Only held for the lifetime of a session, see `preshared_keys`.
+    pub preshared_key: Option<PresharedKey>,
+    // Latest runtime state of this clients peer, refreshed by the usage loop.
+    pub stats: Option<PeerStats>,
+    // The overage policy applied to this session, once it exceeded its allowance.
+    pub overage: Option<OveragePolicy>,
+    // The exit node this session is chained through.
+    pub exit_node: Option<String>,
+    // Public address this sessions traffic leaves the node from, when the node has several.
+    pub exit_address: Option<Ipv4Addr>,
+    // What the client was told to route through the tunnel, kept to render its config again.
+    pub allowed_ips: Vec<IpNet>,
+    // Authenticates requests for this sessions config over HTTP, see `client_config_handler`.
+    pub config_token: Option<String>,
 
     usage: Usage,
     valid_pk: bool,
@@ -115,6 +177,12 @@ impl Client {
         self.public_key = client.public_key.clone();
         self.maximums = client.maximums.clone();
         self.connected = client.connected.clone();
+        self.preshared_key = client.preshared_key.clone();
+        self.overage = client.overage;
+        self.exit_node = client.exit_node.clone();
+        self.exit_address = client.exit_address;
+        self.allowed_ips = client.allowed_ips.clone();
+        self.config_token = client.config_token.clone();
         self.usage = client.usage;
         self.valid_pk = client.valid_pk;
 
@@ -125,14 +193,23 @@ impl Client {
         (self.usage.down, self.usage.up)
     }
 
+    pub fn set_stats(&mut self, stats: PeerStats) -> &mut Self {
+        self.stats = Some(stats);
+
+        self
+    }
+
     pub fn set_limit(&mut self, limit: i128) -> &mut Self {
         self.limit = limit;
 
         self
     }
     pub fn set_public_key(mut self, public_key: String) -> Self {
-        if public_key.len() == 44 && public_key.ends_with("=") {
-            self.public_key = public_key.replace(" ", "+").to_string().replace("\n", "").to_string();
+        // Query strings decode '+' as a space, so restore it before validating.
+        let public_key = public_key.replace(" ", "+").replace("\n", "");
+
+        if is_valid_key(&public_key) {
+            self.public_key = public_key;
             self.valid_pk = true;
         }
 
@@ -149,6 +226,21 @@ impl Client {
         &self.sender
     }
 
+    pub fn send_message(&self, message: &ServerMessage) -> bool {
+        let text = match serde_json::to_string(message) {
+            Ok(text) => text,
+            Err(err) => {
+                println!("[err]: Failed to serialize message {:?}, reason: {}", message, err);
+                return false;
+            }
+        };
+
+        match &self.sender {
+            Some(sender) => sender.send(Ok(Message::text(text))).is_ok(),
+            None => false
+        }
+    }
+
     pub fn is_valid(&self) -> bool {
         self.valid_pk
     }
@@ -184,6 +276,10 @@ impl Client {
         }
     }
 
+    pub fn tier(&self) -> &'static str {
+        self.maximums.tier()
+    }
+
     pub fn set_tier(&mut self, tier: Maximums) -> &mut Self {
         self.maximums = tier;
 
@@ -202,6 +298,13 @@ impl Client {
             },
             limit: -1,
             connected: Connection::Disconnected,
+            preshared_key: None,
+            overage: None,
+            exit_node: None,
+            exit_address: None,
+            allowed_ips: vec![],
+            config_token: None,
+            stats: None,
             valid_pk: false
         }
     }
@@ -0,0 +1,102 @@
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DnsProtocol {
+    // DNS-over-HTTPS (RFC 8484).
+    Https,
+    // DNS-over-TLS (RFC 7858).
+    Tls,
+    // Plain DNS over UDP/TCP, for upstreams within a trusted network.
+    Udp
+}
+
+impl DnsProtocol {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "https" | "doh" | "" => Ok(DnsProtocol::Https),
+            "tls" | "dot" => Ok(DnsProtocol::Tls),
+            "udp" | "plain" => Ok(DnsProtocol::Udp),
+            other => Err(format!("unknown dns protocol '{}', expected https, tls or udp", other))
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Https => 443,
+            Self::Tls => 853,
+            Self::Udp => 53
+        }
+    }
+}
+
+// The resolver served to clients on the servers own address of each interface.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsConfig {
+    // Serve the resolver, otherwise clients are given `dns` and resolve wherever it points.
+    pub enabled: bool,
+    pub protocol: DnsProtocol,
+    pub upstreams: Vec<IpAddr>,
+    pub upstream_port: u16,
+    // Name the upstreams certificate is checked against, unused for udp.
+    pub upstream_name: String,
+    // Files of blocked domains, one per line or in hosts format.
+    pub blocklists: Vec<String>,
+    // Seconds between checking the blocklists for changes, 0 loads them once.
+    pub blocklist_reload_seconds: i64
+}
+
+impl DnsConfig {
+    // Forwards to Cloudflare over HTTPS unless configured otherwise.
+    pub fn from_settings(settings: &Config) -> Result<Self, String> {
+        let protocol = DnsProtocol::parse(&settings.get_string("dns_upstream_protocol").unwrap_or_default())?;
+
+        let upstreams = match settings.get::<Vec<String>>("dns_upstreams") {
+            Ok(upstreams) => upstreams.iter()
+                .map(| upstream | upstream.trim().parse::<IpAddr>().map_err(| _ | format!("'{}' is not an IP address", upstream)))
+                .collect::<Result<Vec<IpAddr>, String>>()?,
+            Err(_) => vec![[1, 1, 1, 1].into(), [1, 0, 0, 1].into()]
+        };
+
+        if upstreams.is_empty() {
+            return Err("at least one dns upstream must be given".to_string());
+        }
+
+        let upstream_port = match settings.get_int("dns_upstream_port") {
+            Ok(port) => u16::try_from(port).map_err(| _ | format!("dns_upstream_port {} is not a valid port", port))?,
+            Err(_) => protocol.default_port()
+        };
+
+        Ok(DnsConfig {
+            enabled: settings.get_bool("dns_resolver").unwrap_or(true),
+            protocol,
+            upstreams,
+            upstream_port,
+            upstream_name: settings.get_string("dns_upstream_name").unwrap_or("cloudflare-dns.com".to_string()),
+            blocklists: settings.get::<Vec<String>>("dns_blocklists").unwrap_or_default(),
+            blocklist_reload_seconds: settings.get_int("dns_blocklist_reload_seconds").unwrap_or(30)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn settings(yaml: &str) -> Config {
+        Config::builder().add_source(config::File::from_str(yaml, FileFormat::Yaml)).build().unwrap()
+    }
+
+    #[test]
+    fn defaults_the_upstream_port_to_the_protocol() {
+        assert_eq!(DnsConfig::from_settings(&settings("dns_upstream_protocol: tls")).unwrap().upstream_port, 853);
+        assert_eq!(DnsConfig::from_settings(&settings("dns_upstream_port: 5353")).unwrap().upstream_port, 5353);
+    }
+
+    #[test]
+    fn rejects_upstream_ports_out_of_range() {
+        assert!(DnsConfig::from_settings(&settings("dns_upstream_port: 65536")).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// How a session is given one of the shared exit addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ExitAddressPolicy {
+    // Each session takes the next address in turn.
+    RoundRobin,
+    // Every session of an account leaves through the same address, across sessions and restarts.
+    StickyPerAuthor
+}
+
+impl ExitAddressPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "round-robin" | "" => Ok(ExitAddressPolicy::RoundRobin),
+            "sticky" | "sticky-per-author" => Ok(ExitAddressPolicy::StickyPerAuthor),
+            other => Err(format!("unknown exit address policy '{}', expected round-robin or sticky-per-author", other))
+        }
+    }
+}
+
+// Public addresses peer traffic leaves the node from. They must already be assigned to the egress interface.
+// Without any, every peer is masqueraded behind the egress interfaces own address.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExitAddressConfig {
+    // Shared between sessions, assigned by `policy`.
+    pub shared: Vec<Ipv4Addr>,
+    pub policy: ExitAddressPolicy,
+    // Each held by a single PRO account at a time, PRO sessions fall back to `shared` once all are taken.
+    pub dedicated: Vec<Ipv4Addr>
+}
+
+impl ExitAddressConfig {
+    // Reads `exit_addresses`, `exit_address_policy` and `dedicated_exit_addresses`.
+    pub fn from_settings(settings: &Config) -> Result<Self, String> {
+        let shared = addresses(settings, "exit_addresses")?;
+        let dedicated = addresses(settings, "dedicated_exit_addresses")?;
+
+        if let Some(address) = dedicated.iter().find(| address | shared.contains(address)) {
+            return Err(format!("{} is both a shared and a dedicated exit address", address));
+        }
+
+        if shared.is_empty() && !dedicated.is_empty() {
+            return Err("dedicated exit addresses need shared exit addresses to fall back to".to_string());
+        }
+
+        Ok(ExitAddressConfig {
+            shared,
+            policy: ExitAddressPolicy::parse(&settings.get_string("exit_address_policy").unwrap_or_default())?,
+            dedicated
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.shared.is_empty()
+    }
+}
+
+fn addresses(settings: &Config, key: &str) -> Result<Vec<Ipv4Addr>, String> {
+    settings.get::<Vec<String>>(key).unwrap_or_default().iter()
+        .map(| address | address.trim().parse::<Ipv4Addr>().map_err(| _ | format!("'{}' is not an IPv4 address", address)))
+        .collect()
+}
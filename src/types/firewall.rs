@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FirewallBackend {
+    // iptables-legacy/ip6tables-legacy, as installed by the node image.
+    IptablesLegacy,
+    // A single `inet` table per interface, managed with nft.
+    Nftables
+}
+
+impl FirewallBackend {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "iptables" | "iptables-legacy" | "" => Ok(FirewallBackend::IptablesLegacy),
+            "nft" | "nftables" => Ok(FirewallBackend::Nftables),
+            other => Err(format!("unknown firewall backend '{}', expected iptables-legacy or nftables", other))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirewallConfig {
+    pub backend: FirewallBackend,
+    // Interface peer traffic leaves the node on, detected from the default route when None.
+    pub egress_interface: Option<String>,
+    // Drop traffic between peers (on any interface), so clients cannot reach each other.
+    pub client_isolation: bool,
+    // Redirect all peer DNS to the built-in resolver and reject DNS-over-TLS, so lookups cannot leak.
+    pub dns_leak_protection: bool
+}
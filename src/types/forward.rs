@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// Public ports handed out for port forwarding, `start..=end`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16
+}
+
+impl PortRange {
+    // Written as `40000-40999`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (start, end) = match value.trim().split_once('-') {
+            Some(bounds) => bounds,
+            None => return Err(format!("'{}' is not a port range, expected <start>-<end>", value))
+        };
+
+        let start = start.trim().parse::<u16>().map_err(| _ | format!("'{}' is not a port", start))?;
+        let end = end.trim().parse::<u16>().map_err(| _ | format!("'{}' is not a port", end))?;
+
+        if start == 0 || start > end {
+            return Err(format!("{}-{} is an empty port range", start, end));
+        }
+
+        Ok(PortRange { start, end })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    pub fn ports(&self) -> std::ops::RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+// A public port forwarded (tcp and udp) to the same port on a peers address.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PortForward {
+    pub port: u16,
+    // The client holding the forward, its public key.
+    #[serde(skip)]
+    pub owner: String,
+    #[serde(skip)]
+    pub interface: String,
+    // The peers address on its interface.
+    pub address: Ipv4Addr
+}
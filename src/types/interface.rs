@@ -0,0 +1,219 @@
+use config::Config;
+use ipnet::{IpNet, Ipv4Net};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{Host, Ipv6Prefix, MIN_POOL_PREFIX, MAX_POOL_PREFIX};
+
+// One WireGuard interface served by this node. Peers are assigned to an interface by policy,
+// i.e. a separate interface for PRO users, or a second port for networks which block the first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InterfaceDefinition {
+    pub name: String,
+    pub listen_port: u16,
+    // The servers own address on this interface, inside `pool`.
+    pub address: Ipv4Addr,
+    // Network peers are given addresses from, between a /16 and a /30.
+    pub pool: Ipv4Net,
+    // Tiers (FREE, SUPPORTER, BASIC, PRO, UNASSIGNED) allowed on this interface, empty allows every tier.
+    pub tiers: Vec<String>,
+    pub ipv6_prefix: Option<Ipv6Prefix>
+}
+
+// As written in `configuration/base`, every key but `name` is optional.
+#[derive(Deserialize)]
+struct InterfaceEntry {
+    name: String,
+    listen_port: Option<u16>,
+    address: Option<Ipv4Addr>,
+    pool: Option<Ipv4Net>,
+    tiers: Option<Vec<String>>,
+    ipv6_prefix: Option<String>
+}
+
+impl InterfaceDefinition {
+    pub fn host_ipv4(&self, host: &Host) -> Ipv4Addr {
+        let octets = self.pool.network().octets();
+        Ipv4Addr::new(octets[0], octets[1], host.a, host.b)
+    }
+
+    // The slot of `address`, which must be inside `pool`.
+    pub fn host(&self, address: Ipv4Addr) -> Host {
+        let octets = address.octets();
+
+        Host {
+            interface: self.name.clone(),
+            a: octets[2],
+            b: octets[3],
+            conn_time: chrono::Utc::now()
+        }
+    }
+
+    pub fn host_ipv6(&self, host: &Host) -> Option<Ipv6Addr> {
+        self.ipv6_prefix.as_ref().map(| prefix | prefix.host(host.a, host.b))
+    }
+
+    // The slot held by the server itself.
+    pub fn server_host(&self) -> Host {
+        self.host(self.address)
+    }
+
+    // Every address peers on this interface may be given.
+    pub fn ipv4_network(&self) -> String {
+        self.pool.to_string()
+    }
+
+    // Every network of this interface, routed through the tunnel whatever split a client asks for.
+    pub fn networks(&self) -> Vec<IpNet> {
+        let mut networks: Vec<IpNet> = self.ipv4_network().parse().into_iter().collect();
+
+        if let Some(prefix) = &self.ipv6_prefix {
+            networks.extend(prefix.to_string().parse::<IpNet>());
+        }
+
+        networks
+    }
+
+    // The servers own addresses on this interface, where the built-in resolver listens.
+    pub fn dns_addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![IpAddr::V4(self.address)];
+
+        if let Some(ipv6) = self.host_ipv6(&self.server_host()) {
+            addresses.push(IpAddr::V6(ipv6));
+        }
+
+        addresses
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        // The prefix of the pool, so the kernel routes every peer through the interface.
+        let mut addresses = vec![format!("{}/{}", self.address, self.pool.prefix_len())];
+
+        if let Some(ipv6) = self.host_ipv6(&self.server_host()) {
+            addresses.push(format!("{}/{}", ipv6, self.ipv6_prefix.map(| prefix | prefix.length).unwrap_or(64)));
+        }
+
+        addresses
+    }
+
+    pub fn allows(&self, tier: &str) -> bool {
+        self.tiers.is_empty() || self.tiers.iter().any(| allowed | allowed.eq_ignore_ascii_case(tier))
+    }
+
+    pub fn config_path(&self) -> String {
+        format!("/etc/wireguard/{}.conf", self.name)
+    }
+}
+
+// Reads `interfaces` from configuration. Without it the node serves a single `reseda` interface,
+// configured by the top level `listen_port`, exactly as before interfaces were configurable.
+pub fn interfaces_from_settings(settings: &Config, ipv6_prefix: Option<Ipv6Prefix>) -> Result<Vec<InterfaceDefinition>, String> {
+    let entries = match settings.get::<Vec<InterfaceEntry>>("interfaces") {
+        Ok(entries) => entries,
+        Err(_) => vec![InterfaceEntry {
+            name: "reseda".to_string(),
+            listen_port: match settings.get_int("listen_port") {
+                Ok(port) => Some(u16::try_from(port).map_err(| _ | format!("listen_port {} is not a valid port", port))?),
+                Err(_) => None
+            },
+            address: None,
+            pool: None,
+            tiers: None,
+            ipv6_prefix: None
+        }]
+    };
+
+    let mut interfaces = vec![];
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        // Without its own prefix, each interface takes a distinct subnet of the node prefix.
+        let ipv6_prefix = match entry.ipv6_prefix {
+            Some(value) if value.is_empty() => None,
+            Some(value) => Some(Ipv6Prefix::parse(&value).map_err(| err | format!("interface {}: {}", entry.name, err))?),
+            None => ipv6_prefix.map(| prefix | prefix.subnet(index as u16))
+        };
+
+        // Either may be given alone, the server takes the first address of a pool, the pool is the /16 of an address.
+        let address = entry.address
+            .or(entry.pool.and_then(| pool | pool.hosts().next()))
+            .unwrap_or(Ipv4Addr::new(10, 8 + index as u8, 2, 1));
+
+        let pool = match entry.pool {
+            Some(pool) => pool.trunc(),
+            None => Ipv4Net::new(address, MIN_POOL_PREFIX).map_err(| err | err.to_string())?.trunc()
+        };
+
+        interfaces.push(InterfaceDefinition {
+            listen_port: entry.listen_port.unwrap_or(8443 + index as u16),
+            address,
+            pool,
+            tiers: entry.tiers.unwrap_or_default(),
+            name: entry.name,
+            ipv6_prefix
+        });
+    }
+
+    validate_interfaces(&interfaces)?;
+
+    Ok(interfaces)
+}
+
+fn validate_interfaces(interfaces: &[InterfaceDefinition]) -> Result<(), String> {
+    if interfaces.is_empty() {
+        return Err("at least one interface must be configured".to_string());
+    }
+
+    let mut names = HashSet::new();
+    let mut ports = HashSet::new();
+
+    for (index, interface) in interfaces.iter().enumerate() {
+        if !names.insert(interface.name.clone()) {
+            return Err(format!("interface name {} is used twice", interface.name));
+        }
+
+        if !ports.insert(interface.listen_port) {
+            return Err(format!("listen port {} is used twice", interface.listen_port));
+        }
+
+        let pool = interface.pool;
+
+        if pool.prefix_len() < MIN_POOL_PREFIX || pool.prefix_len() > MAX_POOL_PREFIX {
+            return Err(format!("{} has a pool of {}, which must be between a /{} and a /{}", interface.name, pool, MIN_POOL_PREFIX, MAX_POOL_PREFIX));
+        }
+
+        if !pool.contains(&interface.address) || interface.address == pool.network() || interface.address == pool.broadcast() {
+            return Err(format!("{} has the address {}, which is not a host of its pool {}", interface.name, interface.address, pool));
+        }
+
+        if let Some(other) = interfaces[..index].iter().find(| other | other.pool.contains(&pool.network()) || pool.contains(&other.pool.network())) {
+            return Err(format!("{} shares its pool {} with {}", interface.name, pool, other.name));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn settings(yaml: &str) -> Config {
+        Config::builder().add_source(config::File::from_str(yaml, FileFormat::Yaml)).build().unwrap()
+    }
+
+    #[test]
+    fn reads_the_top_level_listen_port() {
+        let interfaces = interfaces_from_settings(&settings("listen_port: 51820"), None).unwrap();
+
+        assert_eq!(interfaces[0].name, "reseda");
+        assert_eq!(interfaces[0].listen_port, 51820);
+    }
+
+    #[test]
+    fn rejects_listen_ports_out_of_range() {
+        assert!(interfaces_from_settings(&settings("listen_port: 70000"), None).is_err());
+        assert!(interfaces_from_settings(&settings("listen_port: -1"), None).is_err());
+    }
+}
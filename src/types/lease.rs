@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+
+use super::Host;
+
+// The slot a public key was last given, kept after its session closes so a reconnecting client is given
+// the same address, see `address_lease_seconds`.
+#[derive(Debug, Clone)]
+pub struct AddressLease {
+    pub host: Host,
+    // The account the lease was made for.
+    pub author: String,
+    // None while the session is open, the lease runs out `address_lease_seconds` after it closes.
+    pub expires: Option<DateTime<Utc>>
+}
+
+impl AddressLease {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    pub fn holds(&self, host: &Host) -> bool {
+        self.host.interface == host.interface && self.host.a == host.a && self.host.b == host.b
+    }
+}
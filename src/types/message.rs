@@ -0,0 +1,69 @@
+use serde::Serialize;
+
+use super::{ExitRoute, OveragePolicy, PortForward};
+
+// Messages pushed to a client over its WebSocket, serialized in the same
+// `{ "message": .., "type": .. }` shape the hand-written replies use.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "message", rename_all = "snake_case")]
+pub enum ServerMessage {
+    // The server key will change, clients should acknowledge with a `rekey` query.
+    Rekey(RekeyNotice),
+    // The interface now uses the announced key.
+    RekeyComplete(RekeyNotice),
+    // The node is going away, the session will be closed (and its usage recorded) before the deadline.
+    Shutdown(ShutdownNotice),
+    // The session used up its allowance, `policy` is what happens to it now.
+    Overage(OverageNotice),
+    // A public port now forwards (tcp and udp) to the same port on the session address.
+    PortForward(PortForward),
+    // The session now leaves the node from another public address, replacing `exit_address` of the open reply.
+    ExitAddress(ExitAddressNotice),
+    // The exit node of a chained session announced a new key, the inner peer should follow once it is complete.
+    ExitRekey(RekeyNotice),
+    // The exit node of a chained session now uses the announced key.
+    ExitRekeyComplete(RekeyNotice),
+    // The session with the exit node was reopened, replacing `exit_node` and `allowed_ips` of the open reply.
+    ExitNode(ExitNodeNotice),
+    // The exit node could not be reached again, the session is closed.
+    ExitNodeLost(ExitNodeLostNotice)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RekeyNotice {
+    pub server_public_key: String,
+    // RFC3339 time after which the new key is used, whether or not every client acknowledged.
+    pub deadline: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExitAddressNotice {
+    pub exit_address: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExitNodeNotice {
+    pub exit_node: ExitRoute,
+    pub allowed_ips: Vec<String>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExitNodeLostNotice {
+    pub exit_node: String,
+    pub reason: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShutdownNotice {
+    // RFC3339 time by which the node will have exited.
+    pub deadline: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OverageNotice {
+    pub policy: OveragePolicy,
+    pub up: i128,
+    pub down: i128,
+    // Bytes the tier allowed for the session.
+    pub allowance: i128
+}
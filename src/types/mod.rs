@@ -3,9 +3,37 @@ mod params;
 mod client;
 mod wireguard;
 mod usage;
+mod message;
+mod address;
+mod stats;
+mod interface;
+mod firewall;
+mod overage;
+mod chain;
+mod routes;
+mod dns;
+mod forward;
+mod exit_address;
+mod wg_quick;
+mod pool;
+mod lease;
 
 pub use client::*;
 pub use params::*;
 pub use query::*;
 pub use wireguard::*;
-pub use usage::*;
\ No newline at end of file
+pub use usage::*;
+pub use message::*;
+pub use address::*;
+pub use stats::*;
+pub use interface::*;
+pub use firewall::*;
+pub use overage::*;
+pub use chain::*;
+pub use routes::*;
+pub use dns::*;
+pub use forward::*;
+pub use exit_address::*;
+pub use wg_quick::*;
+pub use pool::*;
+pub use lease::*;
\ No newline at end of file
@@ -0,0 +1,73 @@
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+// What happens to a session once its tier allowance is used up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum OveragePolicy {
+    // Warn and close the session, as every tier did before policies existed.
+    Disconnect,
+    // Keep the session, limited to `kbit` in each direction.
+    Throttle { kbit: u32 },
+    // Keep the session as is, the usage beyond the allowance is billed.
+    AllowAndBill
+}
+
+impl OveragePolicy {
+    // Written as `disconnect`, `throttle:<kbit>` or `allow-and-bill`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim().to_lowercase();
+
+        match value.split_once(':') {
+            Some(("throttle", kbit)) => match kbit.trim().parse::<u32>() {
+                Ok(kbit) if kbit > 0 => Ok(OveragePolicy::Throttle { kbit }),
+                _ => Err(format!("'{}' is not a throttle rate in kbit", kbit))
+            },
+            Some(_) => Err(format!("unknown overage policy '{}'", value)),
+            None => match value.as_str() {
+                "disconnect" => Ok(OveragePolicy::Disconnect),
+                "allow-and-bill" => Ok(OveragePolicy::AllowAndBill),
+                _ => Err(format!("unknown overage policy '{}', expected disconnect, throttle:<kbit> or allow-and-bill", value))
+            }
+        }
+    }
+}
+
+// Same form as parsed, and how the overage is noted on the Usage record.
+impl fmt::Display for OveragePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disconnect => write!(f, "disconnect"),
+            Self::Throttle { kbit } => write!(f, "throttle:{}", kbit),
+            Self::AllowAndBill => write!(f, "allow-and-bill")
+        }
+    }
+}
+
+// Paid tiers degrade instead of being cut off, everyone else is disconnected.
+fn default_policy(tier: &str) -> OveragePolicy {
+    match tier {
+        "BASIC" => OveragePolicy::Throttle { kbit: 1_000 },
+        "PRO" => OveragePolicy::AllowAndBill,
+        _ => OveragePolicy::Disconnect
+    }
+}
+
+// Reads `overage_policies`, a map of tier to policy, i.e. `{ BASIC: "throttle:2000" }`.
+// Tiers which are not given keep their default.
+pub fn overage_policies_from_settings(settings: &Config) -> Result<HashMap<String, OveragePolicy>, String> {
+    let mut policies: HashMap<String, OveragePolicy> = ["FREE", "SUPPORTER", "BASIC", "PRO", "UNASSIGNED"].iter()
+        .map(| tier | (tier.to_string(), default_policy(tier)))
+        .collect();
+
+    if let Ok(configured) = settings.get::<HashMap<String, String>>("overage_policies") {
+        for (tier, policy) in configured {
+            let policy = OveragePolicy::parse(&policy).map_err(| err | format!("tier {}: {}", tier, err))?;
+            policies.insert(tier.to_uppercase(), policy);
+        }
+    }
+
+    Ok(policies)
+}
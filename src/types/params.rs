@@ -4,4 +4,25 @@ use serde::{Deserialize, Serialize};
 pub struct QueryParameters {
     pub author: String,
     pub public_key: String,
-}
\ No newline at end of file
+}
+// How `/config` renders a sessions config.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    // The wg-quick file itself.
+    #[default]
+    Conf,
+    // A QR code of the file, as scanned by the WireGuard mobile apps.
+    Png,
+    Svg,
+    // A QR code drawn with unicode blocks, for terminals.
+    Text
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfigRequest {
+    #[serde(default)]
+    pub format: ConfigFormat,
+    // Filled into the config when given, it must match the sessions public key and is never kept.
+    pub private_key: Option<String>
+}
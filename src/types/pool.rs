@@ -0,0 +1,277 @@
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
+
+// Peers are told apart by the last two octets of their address (see `Host`), so a pool spans at most a /16.
+pub const MIN_POOL_PREFIX: u8 = 16;
+// Leaves the network, broadcast and server addresses with at least one address for a peer.
+pub const MAX_POOL_PREFIX: u8 = 30;
+
+// The addresses of an interface peers are given, one bit each. The network, broadcast and servers own
+// address are held from the start and never released. As a pool is never wider than a /16, no peer is given
+// an address ending in .0.0 or .255.255, whose last two octets (0 and 0xffff) are reserved by tc shaping.
+#[derive(Debug, Clone)]
+pub struct AddressPool {
+    network: Ipv4Net,
+    server: Ipv4Addr,
+    // One bit per address of `network`, set when held.
+    held: Vec<u64>,
+    // One bit per word of `held`, set once every address in it is held. A free address is then found by
+    // looking at no more than 16 words of each, whatever the size of the pool.
+    full: Vec<u64>
+}
+
+impl AddressPool {
+    pub fn new(network: Ipv4Net, server: Ipv4Addr) -> Self {
+        // Checked when interfaces are read, see `interfaces_from_settings`.
+        assert!(
+            (MIN_POOL_PREFIX..=MAX_POOL_PREFIX).contains(&network.prefix_len()),
+            "[err]: Invalid pool {}. Reason: must be between a /{} and a /{}", network, MIN_POOL_PREFIX, MAX_POOL_PREFIX
+        );
+
+        let network = network.trunc();
+        let size = 1usize << (32 - network.prefix_len());
+        let words = size.div_ceil(64);
+
+        let mut pool = AddressPool {
+            network,
+            server,
+            held: vec![0; words],
+            full: vec![0; words.div_ceil(64)]
+        };
+
+        // Bits past the end of a pool smaller than a word, and words past the end of `held`, are never free.
+        if size < 64 {
+            pool.held[0] = !0 << size;
+        }
+
+        for word in words..pool.full.len() * 64 {
+            pool.full[word / 64] |= 1 << (word % 64);
+        }
+
+        for reserved in [0, size - 1, pool.index(server).unwrap_or(0)] {
+            pool.set(reserved, true);
+        }
+
+        pool
+    }
+
+    // The lowest address not yet held.
+    pub fn next_free(&self) -> Option<Ipv4Addr> {
+        let (summary_index, summary) = self.full.iter().enumerate().find(| (_, summary) | **summary != !0)?;
+        let word = summary_index * 64 + (!summary).trailing_zeros() as usize;
+        let bit = (!self.held[word]).trailing_zeros() as usize;
+
+        Some(self.address(word * 64 + bit))
+    }
+
+    pub fn hold(&mut self, address: Ipv4Addr) -> Result<(), String> {
+        let index = match self.index(address) {
+            Some(index) => index,
+            None => return Err(format!("{} is outside of the pool {}", address, self.network))
+        };
+
+        if self.is_set(index) {
+            return Err(format!("{} is already held", address));
+        }
+
+        self.set(index, true);
+
+        Ok(())
+    }
+
+    // False when the address was not held, or is one of the reserved addresses.
+    pub fn release(&mut self, address: Ipv4Addr) -> bool {
+        match self.index(address) {
+            Some(index) if !self.is_reserved(address) && self.is_set(index) => {
+                self.set(index, false);
+                true
+            },
+            _ => false
+        }
+    }
+
+    pub fn is_held(&self, address: Ipv4Addr) -> bool {
+        matches!(self.index(address), Some(index) if self.is_set(index))
+    }
+
+    // Every address held by a peer, the reserved addresses left out.
+    pub fn held(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.held.iter()
+            .enumerate()
+            .filter(| (_, word) | **word != 0)
+            .flat_map(| (word, bits) | (0..64).filter(move | bit | bits & (1u64 << bit) != 0).map(move | bit | word * 64 + bit))
+            .filter(| index | *index < self.size())
+            .map(| index | self.address(index))
+            .filter(| address | !self.is_reserved(*address))
+    }
+
+    fn is_reserved(&self, address: Ipv4Addr) -> bool {
+        address == self.network.network() || address == self.network.broadcast() || address == self.server
+    }
+
+    fn size(&self) -> usize {
+        1usize << (32 - self.network.prefix_len())
+    }
+
+    fn index(&self, address: Ipv4Addr) -> Option<usize> {
+        match self.network.contains(&address) {
+            true => Some((u32::from(address) - u32::from(self.network.network())) as usize),
+            false => None
+        }
+    }
+
+    fn address(&self, index: usize) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network.network()) + index as u32)
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.held[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize, held: bool) {
+        let word = index / 64;
+
+        match held {
+            true => self.held[word] |= 1 << (index % 64),
+            false => self.held[word] &= !(1 << (index % 64))
+        }
+
+        match self.held[word] == !0 {
+            true => self.full[word / 64] |= 1 << (word % 64),
+            false => self.full[word / 64] &= !(1 << (word % 64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(network: &str, server: &str) -> AddressPool {
+        AddressPool::new(network.parse().unwrap(), server.parse().unwrap())
+    }
+
+    fn address(value: &str) -> Ipv4Addr {
+        value.parse().unwrap()
+    }
+
+    // Holds the next free address until the pool runs out, returning every address given.
+    fn exhaust(pool: &mut AddressPool) -> Vec<Ipv4Addr> {
+        let mut given = vec![];
+
+        while let Some(next) = pool.next_free() {
+            pool.hold(next).unwrap();
+            given.push(next);
+        }
+
+        given
+    }
+
+    #[test]
+    fn reserves_network_broadcast_and_server() {
+        let mut pool = pool("10.8.0.0/30", "10.8.0.1");
+
+        assert_eq!(exhaust(&mut pool), vec![address("10.8.0.2")]);
+        assert!(pool.hold(address("10.8.0.0")).is_err());
+        assert!(pool.hold(address("10.8.0.3")).is_err());
+        assert!(!pool.release(address("10.8.0.1")));
+        assert!(!pool.release(address("10.8.0.3")));
+        assert_eq!(pool.held().collect::<Vec<Ipv4Addr>>(), vec![address("10.8.0.2")]);
+    }
+
+    #[test]
+    fn gives_out_every_host_once() {
+        let mut pool = pool("10.8.2.0/24", "10.8.2.1");
+        let given = exhaust(&mut pool);
+
+        assert_eq!(given.len(), 253);
+        assert_eq!(given.first(), Some(&address("10.8.2.2")));
+        assert_eq!(given.last(), Some(&address("10.8.2.254")));
+        assert_eq!(pool.next_free(), None);
+    }
+
+    #[test]
+    fn reuses_released_addresses() {
+        let mut pool = pool("10.8.2.0/24", "10.8.2.1");
+        exhaust(&mut pool);
+
+        assert!(pool.release(address("10.8.2.100")));
+        assert!(!pool.release(address("10.8.2.100")));
+        assert!(!pool.is_held(address("10.8.2.100")));
+        assert_eq!(pool.next_free(), Some(address("10.8.2.100")));
+
+        pool.hold(address("10.8.2.100")).unwrap();
+        assert_eq!(pool.next_free(), None);
+    }
+
+    #[test]
+    fn rejects_addresses_outside_the_pool_or_held() {
+        let mut pool = pool("10.8.2.0/24", "10.8.2.1");
+
+        assert!(pool.hold(address("10.8.3.1")).is_err());
+        assert!(!pool.release(address("10.8.3.1")));
+        assert!(!pool.is_held(address("10.8.3.1")));
+
+        pool.hold(address("10.8.2.50")).unwrap();
+        assert!(pool.hold(address("10.8.2.50")).is_err());
+    }
+
+    #[test]
+    fn crosses_word_boundaries() {
+        let mut pool = pool("10.8.2.0/24", "10.8.2.1");
+
+        for host in 2..64 {
+            pool.hold(Ipv4Addr::new(10, 8, 2, host)).unwrap();
+        }
+
+        // The first word is full, the next free address is the first of the second word.
+        assert_eq!(pool.next_free(), Some(address("10.8.2.64")));
+
+        assert!(pool.release(address("10.8.2.63")));
+        assert_eq!(pool.next_free(), Some(address("10.8.2.63")));
+
+        pool.hold(address("10.8.2.63")).unwrap();
+        pool.hold(address("10.8.2.64")).unwrap();
+        assert_eq!(pool.next_free(), Some(address("10.8.2.65")));
+    }
+
+    #[test]
+    fn crosses_summary_word_boundaries() {
+        // A /16 spans 1024 words, so 16 summary words, the first covering 10.8.0.0 - 10.8.15.255.
+        let mut pool = pool("10.8.0.0/16", "10.8.0.1");
+
+        for index in 2..4096u32 {
+            pool.hold(Ipv4Addr::from(u32::from(address("10.8.0.0")) + index)).unwrap();
+        }
+
+        assert_eq!(pool.next_free(), Some(address("10.8.16.0")));
+
+        assert!(pool.release(address("10.8.15.255")));
+        assert_eq!(pool.next_free(), Some(address("10.8.15.255")));
+    }
+
+    #[test]
+    fn never_gives_out_reserved_shaping_ids() {
+        let mut pool = pool("10.8.0.0/16", "10.8.2.1");
+        let given = exhaust(&mut pool);
+
+        assert_eq!(given.len(), 65533);
+        assert_eq!(given.first(), Some(&address("10.8.0.1")));
+        assert!(given.iter().all(| address | !matches!(address.octets(), [_, _, 0, 0] | [_, _, 255, 255])));
+        assert_eq!(pool.held().count(), 65533);
+    }
+
+    #[test]
+    fn handles_pools_smaller_than_a_word() {
+        let mut pool = pool("10.8.2.8/29", "10.8.2.9");
+
+        assert_eq!(exhaust(&mut pool), (10..15).map(| host | Ipv4Addr::new(10, 8, 2, host)).collect::<Vec<Ipv4Addr>>());
+        assert!(pool.hold(address("10.8.2.16")).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_pools_wider_than_a_slash_16() {
+        pool("10.0.0.0/15", "10.0.0.1");
+    }
+}
@@ -1,15 +1,28 @@
 use serde::{Deserialize, Deserializer};
 
+use super::RouteRequest;
+
 #[derive(Debug)]
 pub enum Query {
     Open,
     Close,
+    Rekey,
+    PortForward,
     None
 }
 
 #[derive(Debug, Deserialize)]
 pub struct StartQuery {
-    pub query_type: Query
+    pub query_type: Query,
+    // Name of the interface to open on, any interface serving the clients tier when absent.
+    pub interface: Option<String>,
+    // Name of an exit node (see `chain_exit_nodes`) the session should leave the network through.
+    pub exit_node: Option<String>,
+    // Public key of the clients inner peer with the exit node, required along with `exit_node`.
+    pub exit_public_key: Option<String>,
+    // Split tunnel of the session, given as `include`, `exclude` and `lan_bypass` alongside the other keys.
+    #[serde(flatten)]
+    pub routes: RouteRequest
 }
 
 impl<'de> Deserialize<'de> for Query {
@@ -21,6 +34,8 @@ impl<'de> Deserialize<'de> for Query {
         let state = match s.as_str() {
             "open" => Query::Open,
             "close" => Query::Close,
+            "rekey" => Query::Rekey,
+            "port_forward" => Query::PortForward,
             _ => Query::None,
         };
         Ok(state)
@@ -0,0 +1,252 @@
+use config::Config;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+// Ranges a client reaches directly, not through the tunnel, when it asks to bypass its LAN.
+const LAN_RANGES: [&str; 7] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "fc00::/7",
+    "fe80::/10"
+];
+
+// Keeps a client from growing its peer into thousands of AllowedIPs.
+const MAX_ROUTES: usize = 64;
+
+// The split tunnel a client asks for in the `open` query.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RouteRequest {
+    // Ranges to route through the tunnel, every allowed route when empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    // Ranges to keep out of the tunnel, taken out of `include`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Keep private, link-local and multicast ranges out of the tunnel.
+    #[serde(default)]
+    pub lan_bypass: bool
+}
+
+// What clients may route through this node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoutePolicy {
+    // Every included range must lie within one of these.
+    pub allowed: Vec<IpNet>,
+    // Whether clients may keep ranges (including their LAN) out of the tunnel, off forces a full tunnel.
+    pub allow_exclusions: bool
+}
+
+impl RoutePolicy {
+    // Reads `allowed_routes`, a list of CIDRs, and `allow_route_exclusions`.
+    pub fn from_settings(settings: &Config) -> Result<Self, String> {
+        let allowed = match settings.get::<Vec<String>>("allowed_routes") {
+            Ok(routes) => parse_routes(&routes)?,
+            Err(_) => parse_routes(&["0.0.0.0/0".to_string(), "::/0".to_string()])?
+        };
+
+        if allowed.is_empty() {
+            return Err("at least one route must be allowed".to_string());
+        }
+
+        Ok(RoutePolicy {
+            allowed,
+            allow_exclusions: settings.get_bool("allow_route_exclusions").unwrap_or(true)
+        })
+    }
+
+    // The ranges a client routes through the tunnel, see `with_tunnel` for its AllowedIPs.
+    pub fn routes(&self, request: &RouteRequest) -> Result<Vec<IpNet>, String> {
+        if request.include.len() > MAX_ROUTES || request.exclude.len() > MAX_ROUTES {
+            return Err(format!("at most {} routes may be included or excluded", MAX_ROUTES));
+        }
+
+        let included = match request.include.is_empty() {
+            true => self.allowed.clone(),
+            false => parse_routes(&request.include)?
+        };
+
+        if let Some(route) = included.iter().find(| route | !self.allowed.iter().any(| allowed | allowed.contains(*route))) {
+            return Err(format!("{} is not routed by this server", route));
+        }
+
+        let mut excluded = parse_routes(&request.exclude)?;
+
+        if request.lan_bypass {
+            excluded.extend(LAN_RANGES.iter().filter_map(| range | range.parse::<IpNet>().ok()));
+        }
+
+        if !excluded.is_empty() && !self.allow_exclusions {
+            return Err("this server does not allow excluding routes".to_string());
+        }
+
+        let mut routes = included;
+
+        for exclusion in &excluded {
+            routes = routes.into_iter()
+                .flat_map(| route | subtract(route, exclusion))
+                .collect();
+        }
+
+        Ok(IpNet::aggregate(&routes))
+    }
+}
+
+// The AllowedIPs of the clients side of the tunnel. `tunnel` are the networks of the interface the client
+// is on, which are always routed so the server (and its other services) stay reachable.
+pub fn with_tunnel(routes: &[IpNet], tunnel: &[IpNet]) -> Vec<IpNet> {
+    let mut routes = routes.to_vec();
+    routes.extend(tunnel.iter().map(| network | network.trunc()));
+
+    IpNet::aggregate(&routes)
+}
+
+fn parse_routes(routes: &[String]) -> Result<Vec<IpNet>, String> {
+    routes.iter()
+        .map(| route | route.trim().parse::<IpNet>()
+            .map(| network | network.trunc())
+            .map_err(| _ | format!("'{}' is not a CIDR range", route)))
+        .collect()
+}
+
+// `route` without `exclusion`. CIDR ranges either nest or are disjoint, so the exclusion is carved out
+// by halving the route until the halves no longer overlap it.
+fn subtract(route: IpNet, exclusion: &IpNet) -> Vec<IpNet> {
+    if exclusion.contains(&route) {
+        return vec![];
+    }
+
+    if !route.contains(exclusion) {
+        return vec![route];
+    }
+
+    match route.subnets(route.prefix_len() + 1) {
+        Ok(halves) => halves.flat_map(| half | subtract(half, exclusion)).collect(),
+        Err(_) => vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn policy(allowed: &[&str], allow_exclusions: bool) -> RoutePolicy {
+        RoutePolicy {
+            allowed: allowed.iter().map(| route | route.parse().unwrap()).collect(),
+            allow_exclusions
+        }
+    }
+
+    fn request(include: &[&str], exclude: &[&str], lan_bypass: bool) -> RouteRequest {
+        RouteRequest {
+            include: include.iter().map(| route | route.to_string()).collect(),
+            exclude: exclude.iter().map(| route | route.to_string()).collect(),
+            lan_bypass
+        }
+    }
+
+    fn nets(routes: &[&str]) -> Vec<IpNet> {
+        routes.iter().map(| route | route.parse().unwrap()).collect()
+    }
+
+    fn routes_to(routes: &[IpNet], address: &str) -> bool {
+        let address = address.parse::<IpAddr>().unwrap();
+        routes.iter().any(| route | route.contains(&address))
+    }
+
+    #[test]
+    fn routes_everything_allowed_by_default() {
+        let routes = policy(&["0.0.0.0/0", "::/0"], true).routes(&RouteRequest::default()).unwrap();
+
+        assert_eq!(routes, nets(&["0.0.0.0/0", "::/0"]));
+    }
+
+    #[test]
+    fn carves_an_exclusion_out_of_the_full_tunnel() {
+        let routes = policy(&["0.0.0.0/0"], true).routes(&request(&[], &["10.0.0.0/8"], false)).unwrap();
+
+        assert_eq!(routes, nets(&[
+            "0.0.0.0/5", "8.0.0.0/7", "11.0.0.0/8", "12.0.0.0/6", "16.0.0.0/4", "32.0.0.0/3", "64.0.0.0/2", "128.0.0.0/1"
+        ]));
+    }
+
+    #[test]
+    fn lan_bypass_excludes_every_lan_range() {
+        let routes = policy(&["0.0.0.0/0", "::/0"], true).routes(&request(&[], &[], true)).unwrap();
+
+        for lan in ["10.1.2.3", "172.16.0.1", "172.31.255.254", "192.168.1.1", "169.254.1.1", "224.0.0.251", "fd00::1", "fe80::1"] {
+            assert!(!routes_to(&routes, lan), "{} should bypass the tunnel", lan);
+        }
+
+        for public in ["1.1.1.1", "172.32.0.1", "192.169.0.1", "11.0.0.1", "2001:db8::1", "fec0::1"] {
+            assert!(routes_to(&routes, public), "{} should be tunnelled", public);
+        }
+    }
+
+    #[test]
+    fn merges_adjacent_prefixes() {
+        let routes = policy(&["0.0.0.0/0"], true).routes(&request(&["10.0.0.0/25", "10.0.0.128/25", "10.0.1.0/24"], &[], false)).unwrap();
+
+        assert_eq!(routes, nets(&["10.0.0.0/23"]));
+    }
+
+    #[test]
+    fn excludes_from_ipv6_routes() {
+        let routes = policy(&["::/0"], true).routes(&request(&["2001:db8::/32"], &["2001:db8:8000::/33"], false)).unwrap();
+
+        assert_eq!(routes, nets(&["2001:db8::/33"]));
+    }
+
+    #[test]
+    fn excluding_a_whole_route_removes_it() {
+        let routes = policy(&["0.0.0.0/0"], true).routes(&request(&["10.1.0.0/16", "8.8.8.0/24"], &["10.0.0.0/8"], false)).unwrap();
+
+        assert_eq!(routes, nets(&["8.8.8.0/24"]));
+    }
+
+    #[test]
+    fn truncates_host_bits() {
+        let routes = policy(&["0.0.0.0/0"], true).routes(&request(&["192.0.2.77/24"], &[], false)).unwrap();
+
+        assert_eq!(routes, nets(&["192.0.2.0/24"]));
+    }
+
+    #[test]
+    fn rejects_requests_outside_the_policy() {
+        let restricted = policy(&["10.0.0.0/8"], false);
+
+        assert!(restricted.routes(&request(&["0.0.0.0/0"], &[], false)).is_err());
+        assert!(restricted.routes(&request(&["10.1.0.0/16"], &["10.1.1.0/24"], false)).is_err());
+        assert!(restricted.routes(&request(&[], &[], true)).is_err());
+        assert!(restricted.routes(&request(&["not a route"], &[], false)).is_err());
+
+        let too_many: Vec<String> = (0..=MAX_ROUTES).map(| index | format!("10.0.{}.0/24", index)).collect();
+        let too_many: Vec<&str> = too_many.iter().map(| route | route.as_str()).collect();
+
+        assert!(restricted.routes(&request(&too_many, &[], false)).is_err());
+        assert_eq!(restricted.routes(&request(&["10.1.0.0/16"], &[], false)).unwrap(), nets(&["10.1.0.0/16"]));
+    }
+
+    #[test]
+    fn subtract_leaves_disjoint_routes_alone() {
+        let route: IpNet = "192.0.2.0/24".parse().unwrap();
+
+        assert_eq!(subtract(route, &"198.51.100.0/24".parse().unwrap()), vec![route]);
+        assert!(subtract(route, &"192.0.0.0/16".parse().unwrap()).is_empty());
+        assert_eq!(subtract(route, &"192.0.2.0/25".parse().unwrap()), nets(&["192.0.2.128/25"]));
+    }
+
+    #[test]
+    fn tunnel_networks_are_always_routed() {
+        let routes = policy(&["0.0.0.0/0", "::/0"], true).routes(&request(&[], &[], true)).unwrap();
+        let allowed_ips = with_tunnel(&routes, &nets(&["10.8.0.0/16", "fd10:8::/112"]));
+
+        assert!(routes_to(&allowed_ips, "10.8.2.1"));
+        assert!(routes_to(&allowed_ips, "fd10:8::1"));
+        assert!(!routes_to(&allowed_ips, "10.9.0.1"));
+        assert!(!routes_to(&allowed_ips, "192.168.1.1"));
+    }
+}
@@ -0,0 +1,24 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::net::SocketAddr;
+
+// Runtime state of one peer as reported by `wg show <interface> dump`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub public_key: String,
+    // Where the peer last sent an authenticated packet from, None before its first handshake.
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<String>,
+    pub latest_handshake: Option<DateTime<Utc>>,
+    // Bytes received from the peer.
+    pub rx: u64,
+    // Bytes sent to the peer.
+    pub tx: u64,
+    pub persistent_keepalive: Option<u16>
+}
+
+impl PeerStats {
+    pub fn handshake_age(&self) -> Option<Duration> {
+        self.latest_handshake.map(| handshake | Utc::now() - handshake)
+    }
+}
@@ -0,0 +1,478 @@
+use std::fmt;
+
+// Every key wg-quick(8) understands, including those it hands to wg(8). Keys are matched without regard
+// to case, as wg-quick does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    // [Interface]
+    Address,
+    Dns,
+    Mtu,
+    Table,
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+    SaveConfig,
+    PrivateKey,
+    ListenPort,
+    FwMark,
+    // [Peer]
+    PublicKey,
+    PresharedKey,
+    AllowedIps,
+    Endpoint,
+    PersistentKeepalive,
+    // Anything else, kept (and emitted) as written.
+    Other(String)
+}
+
+const KEYS: [Key; 17] = [
+    Key::Address, Key::Dns, Key::Mtu, Key::Table, Key::PreUp, Key::PostUp, Key::PreDown, Key::PostDown, Key::SaveConfig,
+    Key::PrivateKey, Key::ListenPort, Key::FwMark,
+    Key::PublicKey, Key::PresharedKey, Key::AllowedIps, Key::Endpoint, Key::PersistentKeepalive
+];
+
+impl Key {
+    pub fn parse(name: &str) -> Self {
+        KEYS.iter()
+            .find(| key | key.name().eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| Key::Other(name.to_string()))
+    }
+
+    // As written in the wg-quick documentation.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Address => "Address",
+            Self::Dns => "DNS",
+            Self::Mtu => "MTU",
+            Self::Table => "Table",
+            Self::PreUp => "PreUp",
+            Self::PostUp => "PostUp",
+            Self::PreDown => "PreDown",
+            Self::PostDown => "PostDown",
+            Self::SaveConfig => "SaveConfig",
+            Self::PrivateKey => "PrivateKey",
+            Self::ListenPort => "ListenPort",
+            Self::FwMark => "FwMark",
+            Self::PublicKey => "PublicKey",
+            Self::PresharedKey => "PresharedKey",
+            Self::AllowedIps => "AllowedIPs",
+            Self::Endpoint => "Endpoint",
+            Self::PersistentKeepalive => "PersistentKeepalive",
+            Self::Other(name) => name
+        }
+    }
+
+    fn is_peer_key(&self) -> bool {
+        matches!(self, Self::PublicKey | Self::PresharedKey | Self::AllowedIps | Self::Endpoint | Self::PersistentKeepalive)
+    }
+
+    // Rejects values wg-quick (or wg) would refuse, so a bad file fails on import rather than on `up`.
+    fn validate(&self, value: &str) -> Result<(), String> {
+        let valid = match self {
+            Self::ListenPort => value.parse::<u16>().is_ok(),
+            Self::Mtu => value.parse::<u16>().is_ok(),
+            Self::SaveConfig => matches!(value, "true" | "false"),
+            Self::PersistentKeepalive => value == "off" || value.parse::<u16>().is_ok(),
+            Self::FwMark => value == "off" || value.parse::<u32>().is_ok()
+                || matches!(value.strip_prefix("0x").map(| hex | u32::from_str_radix(hex, 16)), Some(Ok(_))),
+            _ => true
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(format!("'{}' is not a valid {}", value, self.name()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WgQuickError {
+    // 1-based, 0 when the error is not tied to a line.
+    pub line: usize,
+    pub reason: String
+}
+
+impl fmt::Display for WgQuickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.reason),
+            line => write!(f, "line {}: {}", line, self.reason)
+        }
+    }
+}
+
+impl std::error::Error for WgQuickError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    // `raw` is the line as read, emitted unchanged until the entry is modified.
+    Entry { key: Key, value: String, raw: Option<String> },
+    // Comments and blank lines, as written.
+    Text(String)
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Section {
+    // The header as read, i.e. `[interface]`, emitted unchanged.
+    header: Option<String>,
+    pub lines: Vec<Line>
+}
+
+impl Section {
+    // The last value given for `key`, which is the one wg-quick uses.
+    pub fn get(&self, key: &Key) -> Option<&str> {
+        self.entries(key).last()
+    }
+
+    // Replaces the first entry of `key` in place (dropping any later ones), or appends it.
+    pub fn set(&mut self, key: Key, value: impl Into<String>) -> &mut Self {
+        let value = value.into();
+        let mut replaced = false;
+
+        self.lines.retain_mut(| line | match line {
+            Line::Entry { key: existing, value: existing_value, raw } if *existing == key => {
+                if replaced {
+                    return false;
+                }
+
+                *existing_value = value.clone();
+                *raw = None;
+                replaced = true;
+                true
+            },
+            _ => true
+        });
+
+        if !replaced {
+            self.push(key, value);
+        }
+
+        self
+    }
+
+    // Adds another entry of `key`, i.e. a further PostUp command.
+    pub fn push(&mut self, key: Key, value: impl Into<String>) -> &mut Self {
+        self.lines.push(Line::Entry { key, value: value.into(), raw: None });
+
+        self
+    }
+
+    // Every entry, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &str)> {
+        self.lines.iter().filter_map(| line | match line {
+            Line::Entry { key, value, .. } => Some((key, value.as_str())),
+            Line::Text(_) => None
+        })
+    }
+
+    fn entries<'a>(&'a self, key: &Key) -> impl Iterator<Item = &'a str> {
+        let key = key.clone();
+
+        self.iter().filter(move | (existing, _) | **existing == key).map(| (_, value) | value)
+    }
+
+    fn write(&self, out: &mut String, name: &str) {
+        let mut write_line = | line: &str | {
+            out.push_str(line);
+            out.push('\n');
+        };
+
+        write_line(self.header.as_deref().unwrap_or(name));
+
+        for line in &self.lines {
+            match line {
+                Line::Entry { raw: Some(raw), .. } => write_line(raw),
+                Line::Entry { key, value, raw: None } => write_line(&format!("{} = {}", key.name(), value)),
+                Line::Text(text) => write_line(text)
+            }
+        }
+    }
+}
+
+// A wg-quick(8) configuration file. Parsing keeps comments, blank lines, key spelling and section order,
+// so a file which is parsed and emitted again is unchanged, apart from the entries that were modified.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WgQuickConfig {
+    // Comments and blank lines before the first section.
+    pub preamble: Vec<String>,
+    pub interface: Section,
+    pub peers: Vec<Section>,
+    // Peers written before the interface section, usually none.
+    interface_position: usize,
+    trailing_newline: bool
+}
+
+#[derive(PartialEq)]
+enum Current {
+    None,
+    Interface,
+    Peer
+}
+
+impl WgQuickConfig {
+    pub fn new() -> Self {
+        WgQuickConfig {
+            trailing_newline: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, WgQuickError> {
+        let mut config = WgQuickConfig::default();
+        let mut current = Current::None;
+        let mut seen_interface = false;
+
+        let mut lines: Vec<&str> = contents.split('\n').collect();
+
+        // A file ending in a newline splits into a last, empty, line which is not part of the file.
+        config.trailing_newline = lines.len() > 1 && lines.last() == Some(&"");
+
+        if config.trailing_newline {
+            lines.pop();
+        }
+
+        for (index, raw) in lines.into_iter().enumerate() {
+            let error = | reason: String | WgQuickError { line: index + 1, reason };
+
+            // wg-quick ignores everything after a `#`.
+            let line = raw.split('#').next().unwrap_or_default().trim();
+
+            if line.is_empty() {
+                match current {
+                    Current::None => config.preamble.push(raw.to_string()),
+                    Current::Interface => config.interface.lines.push(Line::Text(raw.to_string())),
+                    Current::Peer => if let Some(peer) = config.peers.last_mut() {
+                        peer.lines.push(Line::Text(raw.to_string()));
+                    }
+                }
+
+                continue;
+            }
+
+            if line.starts_with('[') {
+                match line.to_lowercase().as_str() {
+                    "[interface]" if seen_interface => return Err(error("a second [Interface] section".to_string())),
+                    "[interface]" => {
+                        seen_interface = true;
+                        current = Current::Interface;
+                        config.interface.header = Some(raw.to_string());
+                        config.interface_position = config.peers.len();
+                    },
+                    "[peer]" => {
+                        current = Current::Peer;
+                        config.peers.push(Section { header: Some(raw.to_string()), lines: vec![] });
+                    },
+                    other => return Err(error(format!("unknown section {}", other)))
+                }
+
+                continue;
+            }
+
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(error(format!("'{}' is not a key = value pair", line)))
+            };
+
+            let key = Key::parse(name);
+            key.validate(value).map_err(error)?;
+
+            let entry = Line::Entry { key: key.clone(), value: value.to_string(), raw: Some(raw.to_string()) };
+
+            match current {
+                Current::None => return Err(error(format!("{} is outside of a section", name))),
+                Current::Interface if key.is_peer_key() => return Err(error(format!("{} belongs in a [Peer] section", key.name()))),
+                Current::Interface => config.interface.lines.push(entry),
+                Current::Peer if !key.is_peer_key() && !matches!(key, Key::Other(_)) => {
+                    return Err(error(format!("{} belongs in the [Interface] section", key.name())))
+                },
+                Current::Peer => if let Some(peer) = config.peers.last_mut() {
+                    peer.lines.push(entry);
+                }
+            }
+        }
+
+        if !seen_interface {
+            return Err(WgQuickError { line: 0, reason: "missing [Interface] section".to_string() });
+        }
+
+        Ok(config)
+    }
+
+    pub fn read(path: &str) -> Result<Self, WgQuickError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => WgQuickConfig::parse(&contents),
+            Err(err) => Err(WgQuickError { line: 0, reason: format!("unable to read {}, {}", path, err) })
+        }
+    }
+
+    // The interface section alone, as peers of a node are added at runtime rather than from its file.
+    pub fn without_peers(&self) -> Self {
+        WgQuickConfig {
+            preamble: self.preamble.clone(),
+            interface: self.interface.clone(),
+            peers: vec![],
+            interface_position: 0,
+            trailing_newline: self.trailing_newline
+        }
+    }
+}
+
+impl fmt::Display for WgQuickConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Every line is written with a newline, the last one is dropped again if the parsed file had none.
+        let mut out = String::new();
+
+        for line in &self.preamble {
+            out.push_str(line);
+            out.push('\n');
+        }
+
+        for (index, peer) in self.peers.iter().enumerate() {
+            if index == self.interface_position {
+                self.interface.write(&mut out, "[Interface]");
+            }
+
+            peer.write(&mut out, "[Peer]");
+        }
+
+        if self.interface_position >= self.peers.len() {
+            self.interface.write(&mut out, "[Interface]");
+        }
+
+        if !self.trailing_newline && out.ends_with('\n') {
+            out.pop();
+        }
+
+        f.write_str(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "# Managed by hand\n\
+\n\
+[Interface]\n\
+address = 10.8.2.1/16  # server\n\
+PrivateKey=yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n\
+ListenPort = 51820\n\
+MTU = 1380\n\
+PostUp = iptables -A FORWARD -i %i -j ACCEPT\n\
+PostUp = echo up\n\
+Table = off\n\
+\n\
+[peer]\n\
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n\
+AllowedIPs = 10.8.2.2/32, fd10:8::2:2/128\n\
+PersistentKeepalive = 25\n\
+\n\
+[Peer]\n\
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\n\
+AllowedIPs = 10.8.2.3/32\n\
+Endpoint = 192.0.2.1:51820\n";
+
+    #[test]
+    fn round_trips_unchanged() {
+        let config = WgQuickConfig::parse(FILE).unwrap();
+
+        assert_eq!(config.to_string(), FILE);
+        assert_eq!(config.peers.len(), 2);
+    }
+
+    #[test]
+    fn round_trips_without_trailing_newline() {
+        let file = FILE.trim_end_matches('\n');
+
+        assert_eq!(WgQuickConfig::parse(file).unwrap().to_string(), file);
+    }
+
+    #[test]
+    fn keeps_peers_written_before_the_interface() {
+        let file = "[Peer]\nPublicKey = a\n\n[Interface]\nPrivateKey = b\n";
+
+        assert_eq!(WgQuickConfig::parse(file).unwrap().to_string(), file);
+    }
+
+    #[test]
+    fn reads_keys_without_regard_to_case() {
+        let config = WgQuickConfig::parse(FILE).unwrap();
+
+        assert_eq!(config.interface.get(&Key::Address), Some("10.8.2.1/16"));
+        assert_eq!(config.interface.get(&Key::Mtu), Some("1380"));
+        assert_eq!(config.interface.get(&Key::Table), Some("off"));
+        assert_eq!(config.peers[1].get(&Key::Endpoint), Some("192.0.2.1:51820"));
+        // The last of a repeated key is the one wg-quick uses.
+        assert_eq!(config.interface.get(&Key::PostUp), Some("echo up"));
+    }
+
+    #[test]
+    fn set_only_rewrites_the_modified_entry() {
+        let mut config = WgQuickConfig::parse(FILE).unwrap();
+        config.interface.set(Key::ListenPort, "8443").set(Key::PostUp, "true");
+
+        let expected = FILE
+            .replace("ListenPort = 51820", "ListenPort = 8443")
+            .replace("PostUp = iptables -A FORWARD -i %i -j ACCEPT\nPostUp = echo up\n", "PostUp = true\n");
+
+        assert_eq!(config.to_string(), expected);
+    }
+
+    #[test]
+    fn emits_new_entries_in_canonical_spelling() {
+        let mut config = WgQuickConfig::new();
+        config.interface.set(Key::Address, "10.8.0.1/16").set(Key::Dns, "10.8.0.1");
+
+        let mut peer = Section::default();
+        peer.set(Key::AllowedIps, "0.0.0.0/0").set(Key::PersistentKeepalive, "25");
+        config.peers.push(peer);
+
+        let emitted = config.to_string();
+
+        assert_eq!(emitted, "[Interface]\nAddress = 10.8.0.1/16\nDNS = 10.8.0.1\n[Peer]\nAllowedIPs = 0.0.0.0/0\nPersistentKeepalive = 25\n");
+        assert_eq!(WgQuickConfig::parse(&emitted).unwrap().to_string(), emitted);
+    }
+
+    #[test]
+    fn without_peers_keeps_the_interface() {
+        let config = WgQuickConfig::parse(FILE).unwrap().without_peers();
+
+        assert!(config.peers.is_empty());
+        assert!(config.to_string().starts_with("# Managed by hand\n\n[Interface]\naddress = 10.8.2.1/16  # server\n"));
+        assert!(!config.to_string().contains("[peer]"));
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let cases = [
+            ("[Interface]\nListenPort = port\n", 2),
+            ("[Interface]\n[Interface]\n", 2),
+            ("[Interface]\nPublicKey = a\n", 2),
+            ("[Interface]\n[Peer]\nListenPort = 1\n", 3),
+            ("PrivateKey = a\n[Interface]\n", 1),
+            ("[Interface]\nnot a pair\n", 2),
+            ("[Interface]\n[Unknown]\n", 2),
+            ("[Interface]\nPersistentKeepalive = 25\n", 2),
+            ("[Peer]\nPublicKey = a\n", 0)
+        ];
+
+        for (file, line) in cases {
+            match WgQuickConfig::parse(file) {
+                Ok(_) => panic!("{:?} should not parse", file),
+                Err(err) => assert_eq!(err.line, line, "{:?}: {}", file, err)
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_the_special_values() {
+        let file = "[Interface]\nFwMark = 0xca6c\nSaveConfig = true\n[Peer]\nPersistentKeepalive = off\nPresharedKey = a\nUnknownKey = kept\n";
+        let config = WgQuickConfig::parse(file).unwrap();
+
+        assert_eq!(config.peers[0].get(&Key::Other("UnknownKey".to_string())), Some("kept"));
+        assert_eq!(config.to_string(), file);
+    }
+}
@@ -1,16 +1,66 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use config::Config;
+use rand::{rngs::OsRng, RngCore};
 use serde::{Serialize, Deserialize};
-use std::process::{Command, Stdio};
-use std::io::Write;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use std::collections::HashMap;
+
+use super::{ChainConfig, DnsConfig, ExitAddressConfig, PortRange, WgQuickConfig, FirewallBackend, Maximums, FirewallConfig, InterfaceDefinition, Ipv6Prefix, OveragePolicy, RoutePolicy, interfaces_from_settings, overage_policies_from_settings};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct WireGuardConfigFile {
     pub address: String,
     pub name: String,
-    pub post_up: String,
-    pub post_down: String,
-    pub listen_port: String,
+    // Given to clients when the built-in resolver is disabled.
     pub dns: String,
+    pub resolver: DnsConfig,
+    pub backend: String,
+    // Unique-local prefix peers are given IPv6 addresses from, None disables IPv6.
+    pub ipv6_prefix: Option<Ipv6Prefix>,
+    pub interfaces: Vec<InterfaceDefinition>,
+    pub firewall: FirewallConfig,
+    // Keyed by tier, see `overage_policy`.
+    pub overage_policies: HashMap<String, OveragePolicy>,
+    pub chain: ChainConfig,
+    // What clients may ask to route through the tunnel.
+    pub routes: RoutePolicy,
+    // Public ports clients may have forwarded to them, None disables port forwarding.
+    pub port_forward_range: Option<PortRange>,
+    // Public addresses sessions are spread across.
+    pub exit_addresses: ExitAddressConfig,
+    // Hand every session its own preshared key in the open reply.
+    pub preshared_keys: bool,
+    // Written into the config clients are given, see `client_config`.
+    pub client_mtu: u16,
+    pub client_keepalive: u16,
+
+    // Where the node keeps state that must survive a restart, i.e. its key pair.
+    pub state_directory: String,
+    pub private_key: Option<String>,
+    pub private_key_file: Option<String>,
+    // An existing wg-quick file (`import_config`) to take the servers key and any interface keys the node
+    // does not manage itself, i.e. MTU, FwMark or PreUp, from.
+    pub import_config: Option<String>,
+    #[serde(skip)]
+    pub imported: Option<WgQuickConfig>,
+
+    // Hours between server key rotations, 0 disables rotation.
+    pub key_rotation_hours: i64,
+    // Seconds clients are given to acknowledge a new key before it is used regardless.
+    pub key_rotation_grace_seconds: i64,
+    // Seconds between diffing the interface against connected clients, 0 disables it.
+    pub reconcile_interval_seconds: i64,
+    // Minutes without a handshake before a session is closed, 0 disables it.
+    pub idle_timeout_minutes: i64,
+    // Seconds a closed session keeps its address for its public key to reconnect to, 0 disables leases.
+    pub address_lease_seconds: i64,
+    // Only give a lease back to the account it was made for, not to the same key under another account.
+    pub address_lease_by_author: bool,
+    // Seconds a shutdown may spend recording usage of open sessions before the interfaces are taken down.
+    pub shutdown_grace_seconds: i64,
 
     pub database_url: String,
     pub access_key: String,
@@ -33,7 +83,15 @@ impl WireGuardConfigFile {
                     panic!("[err]: Loading environment. Reason: {:?}", err)
                 }
         };
-        
+
+        match public_ip::addr().await {
+            Some(ip) => Self::from_settings(&settings, ip.to_string()),
+            None => panic!("[err]: Unable to retrieve IP address.")
+        }
+    }
+
+    // Reads the configuration of a node reachable at `address`, panicking on any invalid value.
+    pub fn from_settings(settings: &Config, address: String) -> Self {
         let database_url = match settings.get_string("database_auth") {
             Ok(val) => val,
             Err(_) => panic!()
@@ -44,70 +102,298 @@ impl WireGuardConfigFile {
             Err(_) => panic!()
         };
 
-        match public_ip::addr().await {
-            Some(ip) => {
-                let ip_addr = ip.to_string();
-
-                Self {
-                    name: "".to_string(),
-                    address: ip_addr,
-                    post_up: "iptables -A FORWARD -i reseda -j ACCEPT; iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE".to_string(),
-                    post_down: "iptables -A FORWARD -i reseda -j ACCEPT; iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE".to_string(),
-                    dns: "1.1.1.1".to_string(),
-                    listen_port: "8443".to_string(),
-                    database_url,
-                    access_key,
-
-                    location: "".to_string(),
-                    country: "".to_string(),
-                    flag: "".to_string()
-                }
+        // One of cli, netlink (requires the `netlink` feature) or memory.
+        let backend = settings.get_string("wireguard_backend").unwrap_or("cli".to_string());
+
+        let state_directory = settings.get_string("state_directory").unwrap_or("/var/lib/reseda".to_string());
+        let private_key = settings.get_string("private_key").ok();
+        let private_key_file = settings.get_string("private_key_file").ok();
+
+        let import_config = settings.get_string("import_config").ok();
+        let imported = import_config.as_ref().map(| path | match WgQuickConfig::read(path) {
+            Ok(imported) => imported,
+            Err(err) => panic!("[err]: Invalid import_config {}. Reason: {}", path, err)
+        });
+
+        let key_rotation_hours = settings.get_int("key_rotation_hours").unwrap_or(0);
+        let key_rotation_grace_seconds = settings.get_int("key_rotation_grace_seconds").unwrap_or(3600);
+        let reconcile_interval_seconds = settings.get_int("reconcile_interval_seconds").unwrap_or(30);
+        let idle_timeout_minutes = settings.get_int("idle_timeout_minutes").unwrap_or(15);
+        let address_lease_seconds = settings.get_int("address_lease_seconds").unwrap_or(3600);
+        let address_lease_by_author = settings.get_bool("address_lease_by_author").unwrap_or(false);
+        // Kept below the default kubernetes termination grace period of 30s.
+        let shutdown_grace_seconds = settings.get_int("shutdown_grace_seconds").unwrap_or(25);
+
+        // An empty `ipv6_prefix` turns dual-stack off.
+        let ipv6_prefix = match settings.get_string("ipv6_prefix").unwrap_or("fd10:8::/64".to_string()).as_str() {
+            "" => None,
+            value => match Ipv6Prefix::parse(value) {
+                Ok(prefix) => Some(prefix),
+                Err(err) => panic!("[err]: Invalid ipv6_prefix. Reason: {}", err)
+            }
+        };
+
+        let preshared_keys = settings.get_bool("preshared_keys").unwrap_or(false);
+
+        // wg-quick's own default MTU, and a keepalive which holds most NAT mappings open.
+        let client_mtu = match u16::try_from(settings.get_int("client_mtu").unwrap_or(1420)) {
+            Ok(mtu) if mtu >= 1280 => mtu,
+            _ => panic!("[err]: Invalid client_mtu. Reason: must be between 1280 and 65535")
+        };
+
+        let client_keepalive = match u16::try_from(settings.get_int("client_keepalive").unwrap_or(25)) {
+            Ok(keepalive) => keepalive,
+            Err(err) => panic!("[err]: Invalid client_keepalive. Reason: {}", err)
+        };
+
+        let interfaces = match interfaces_from_settings(settings, ipv6_prefix) {
+            Ok(interfaces) => interfaces,
+            Err(err) => panic!("[err]: Invalid interfaces. Reason: {}", err)
+        };
+
+        // One of iptables-legacy or nftables.
+        let firewall = FirewallConfig {
+            backend: match FirewallBackend::parse(&settings.get_string("firewall_backend").unwrap_or_default()) {
+                Ok(backend) => backend,
+                Err(err) => panic!("[err]: Invalid firewall_backend. Reason: {}", err)
             },
-            None => panic!("[err]: Unable to retrieve IP address.")
+            egress_interface: settings.get_string("egress_interface").ok(),
+            client_isolation: settings.get_bool("client_isolation").unwrap_or(true),
+            dns_leak_protection: settings.get_bool("dns_leak_protection").unwrap_or(false)
+        };
+
+        let overage_policies = match overage_policies_from_settings(settings) {
+            Ok(policies) => policies,
+            Err(err) => panic!("[err]: Invalid overage_policies. Reason: {}", err)
+        };
+
+        let routes = match RoutePolicy::from_settings(settings) {
+            Ok(routes) => routes,
+            Err(err) => panic!("[err]: Invalid allowed_routes. Reason: {}", err)
+        };
+
+        let resolver = match DnsConfig::from_settings(settings) {
+            Ok(resolver) => resolver,
+            Err(err) => panic!("[err]: Invalid dns resolver. Reason: {}", err)
+        };
+
+        let port_forward_range = match settings.get_string("port_forward_range").unwrap_or_default().as_str() {
+            "" => None,
+            value => match PortRange::parse(value) {
+                Ok(range) if range.contains(443) || interfaces.iter().any(| interface | range.contains(interface.listen_port)) => {
+                    panic!("[err]: Invalid port_forward_range. Reason: {}-{} overlaps a port the node listens on", range.start, range.end)
+                },
+                Ok(range) => Some(range),
+                Err(err) => panic!("[err]: Invalid port_forward_range. Reason: {}", err)
+            }
+        };
+
+        let exit_addresses = match ExitAddressConfig::from_settings(settings) {
+            Ok(exit_addresses) => exit_addresses,
+            Err(err) => panic!("[err]: Invalid exit_addresses. Reason: {}", err)
+        };
+
+        if firewall.dns_leak_protection && !resolver.enabled {
+            panic!("[err]: Invalid dns_leak_protection. Reason: DNS is redirected to the built-in resolver, which is disabled (dns_resolver)")
+        }
+
+        Self {
+            name: "".to_string(),
+            address,
+            dns: "1.1.1.1".to_string(),
+            resolver,
+            backend,
+            ipv6_prefix,
+            interfaces,
+            firewall,
+            overage_policies,
+            chain: ChainConfig::from_settings(settings),
+            routes,
+            port_forward_range,
+            exit_addresses,
+            preshared_keys,
+            client_mtu,
+            client_keepalive,
+            state_directory,
+            private_key,
+            private_key_file,
+            import_config,
+            imported,
+            key_rotation_hours,
+            key_rotation_grace_seconds,
+            reconcile_interval_seconds,
+            idle_timeout_minutes,
+            address_lease_seconds,
+            address_lease_by_author,
+            shutdown_grace_seconds,
+            database_url,
+            access_key,
+
+            location: "".to_string(),
+            country: "".to_string(),
+            flag: "".to_string()
         }
     }
 }
 
+impl WireGuardConfigFile {
+    pub fn overage_policy(&self, tier: &str) -> OveragePolicy {
+        self.overage_policies.get(tier).copied().unwrap_or(OveragePolicy::Disconnect)
+    }
+
+    // Every rate a peer may be shaped to, by tier or by an overage throttle.
+    pub fn rate_limits(&self) -> Vec<u32> {
+        let mut limits = Maximums::rate_limits();
+
+        for policy in self.overage_policies.values() {
+            if let OveragePolicy::Throttle { kbit } = policy {
+                limits.push(*kbit);
+            }
+        }
+
+        limits.sort();
+        limits.dedup();
+        limits
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    InvalidBase64(String),
+    InvalidLength(usize)
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBase64(reason) => write!(f, "key is not valid base64, {}", reason),
+            Self::InvalidLength(length) => write!(f, "key decodes to {} bytes, expected 32", length),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+// Decodes a base64 WireGuard key, which must be exactly 32 bytes (44 characters encoded).
+pub fn decode_key(key: &str) -> Result<[u8; 32], KeyError> {
+    let decoded = match STANDARD.decode(key.trim()) {
+        Ok(bytes) => Zeroizing::new(bytes),
+        Err(err) => return Err(KeyError::InvalidBase64(err.to_string()))
+    };
+
+    if decoded.len() != 32 {
+        return Err(KeyError::InvalidLength(decoded.len()));
+    }
+
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&decoded);
+
+    Ok(bytes)
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    decode_key(key).is_ok()
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct KeyState {
-    pub private_key: String,
+    // Wiped from memory once the last copy of the key state is dropped.
+    pub private_key: Zeroizing<String>,
     pub public_key: String,
 }
 
 impl KeyState {
     pub fn generate_pair() -> Self {
-        // Generate Private Key
-        let exec_process = Command::new("wg")
-            .arg("genkey")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to generate private key.");
-
-        let output = exec_process.wait_with_output().expect("Failed to read stdout");
-        let private_key = String::from_utf8(output.stdout.to_vec()).unwrap();
-
-        let clone_key = private_key.clone();
-
-        // Generate Public Key
-        let mut exec_process = Command::new("wg")
-            .arg("pubkey")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to generate public key");
-
-        let mut stdin = exec_process.stdin.take().expect("Failed to open stdin");
-        std::thread::spawn(move || {
-            stdin.write_all(&clone_key.as_bytes()).expect("Failed to write to stdin");
-        });
+        KeyState::from_secret(StaticSecret::random())
+    }
+
+    // Used when an operator supplies an existing identity instead of generating one.
+    pub fn from_private_key(private_key: &str) -> Result<Self, KeyError> {
+        let mut bytes = decode_key(private_key)?;
+        let secret = StaticSecret::from(bytes);
+        bytes.zeroize();
 
-        let output = exec_process.wait_with_output().expect("Failed to read stdout");
-        let public_key = String::from_utf8(output.stdout.to_vec()).unwrap();
+        Ok(KeyState::from_secret(secret))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public_key = PublicKey::from(&secret);
 
         KeyState {
-            private_key,
-            public_key
+            private_key: Zeroizing::new(STANDARD.encode(secret.as_bytes())),
+            public_key: STANDARD.encode(public_key.as_bytes())
         }
     }
-}
\ No newline at end of file
+}
+
+// A per-session symmetric key mixed into the WireGuard handshake, adding a post-quantum layer.
+// It is never printed, and is wiped from memory once the session is closed.
+#[derive(Clone, PartialEq)]
+pub struct PresharedKey(Zeroizing<String>);
+
+impl PresharedKey {
+    pub fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(bytes.as_mut());
+
+        PresharedKey(Zeroizing::new(STANDARD.encode(bytes.as_ref())))
+    }
+
+    // A key handed out by another node, i.e. the exit of a chain.
+    pub fn from_encoded(key: &str) -> Result<Self, KeyError> {
+        decode_key(key)?.zeroize();
+
+        Ok(PresharedKey(Zeroizing::new(key.trim().to_string())))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PresharedKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Alice's key pair from RFC 7748, section 6.1.
+    const PRIVATE_KEY: &str = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+    const PUBLIC_KEY: &str = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+
+    #[test]
+    fn derives_the_public_key() {
+        let keys = KeyState::from_private_key(PRIVATE_KEY).unwrap();
+
+        assert_eq!(keys.public_key, PUBLIC_KEY);
+        assert_eq!(keys.private_key.as_str(), PRIVATE_KEY);
+
+        // Surrounding whitespace, i.e. the newline of a key file, is ignored.
+        assert_eq!(KeyState::from_private_key(&format!("{}\n", PRIVATE_KEY)).unwrap().public_key, PUBLIC_KEY);
+    }
+
+    #[test]
+    fn generates_pairs_which_derive_again() {
+        let keys = KeyState::generate_pair();
+
+        assert!(is_valid_key(&keys.public_key));
+        assert_eq!(KeyState::from_private_key(&keys.private_key).unwrap().public_key, keys.public_key);
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        assert!(matches!(decode_key("AAAAAAAAAAAAAAAAAAAAAA=="), Err(KeyError::InvalidLength(16))));
+        assert!(matches!(KeyState::from_private_key(""), Err(KeyError::InvalidLength(0))));
+    }
+
+    #[test]
+    fn rejects_keys_which_are_not_base64() {
+        assert!(matches!(decode_key("not a key, not base64 either, at 44 chars!!"), Err(KeyError::InvalidBase64(_))));
+        assert!(matches!(KeyState::from_private_key("dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LC*="), Err(KeyError::InvalidBase64(_))));
+        assert!(!is_valid_key("hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo"));
+    }
+}
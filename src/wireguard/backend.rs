@@ -0,0 +1,110 @@
+use crate::types::{PeerStats, PresharedKey};
+use std::fmt;
+use std::sync::Arc;
+
+use super::{CliBackend, MemoryBackend};
+
+// A backend is the only thing allowed to touch the kernel (or userspace) WireGuard device.
+// Everything above it speaks in terms of interface names and typed peer descriptions, which lets
+// `WireGuardConfig` run against the in-memory fake without root or wireguard-tools installed.
+pub type Backend = Arc<dyn WireGuardBackend>;
+
+pub trait WireGuardBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn interface_up(&self, interface: &InterfaceSettings) -> Result<(), WireGuardError>;
+    fn interface_down(&self, interface: &str) -> Result<(), WireGuardError>;
+    // Swaps the interface key in place, keeping every peer and its counters. Sessions handshaked with the old
+    // key end, see `complete_rotation`.
+    fn set_private_key(&self, interface: &str, private_key: &str) -> Result<(), WireGuardError>;
+
+    fn add_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError>;
+    fn update_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError>;
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WireGuardError>;
+
+    // Full runtime state of every peer on the interface, everything below is derived from it.
+    fn dump(&self, interface: &str) -> Result<Vec<PeerStats>, WireGuardError>;
+
+    // Every peer currently configured on the interface. Preshared keys are never read back.
+    fn peers(&self, interface: &str) -> Result<Vec<PeerConfig>, WireGuardError> {
+        Ok(self.dump(interface)?.into_iter().map(| stats | PeerConfig {
+            public_key: stats.public_key,
+            allowed_ips: stats.allowed_ips,
+            persistent_keepalive: stats.persistent_keepalive,
+            preshared_key: None
+        }).collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceSettings {
+    pub name: String,
+    pub private_key: String,
+    pub listen_port: u16,
+    // Interface addresses including their prefix length, i.e. 10.8.2.1/16
+    pub addresses: Vec<String>,
+    pub post_up: String,
+    pub post_down: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: String,
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u16>,
+    pub preshared_key: Option<PresharedKey>
+}
+
+#[derive(Debug)]
+pub enum WireGuardError {
+    // The process could not be spawned at all, i.e. `wg` is not on PATH.
+    Spawn { command: String, source: std::io::Error },
+    // The process ran, but exited unsuccessfully.
+    Command { command: String, status: Option<i32>, stderr: String },
+    Parse(String),
+    InvalidKey(String),
+    UnknownInterface(String),
+    UnknownPeer(String),
+    PeerDisconnected(String),
+    Netlink(String),
+    UnsupportedBackend(String)
+}
+
+impl fmt::Display for WireGuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn { command, source } => write!(f, "failed to spawn `{}`: {}", command, source),
+            Self::Command { command, status, stderr } => match status {
+                Some(code) => write!(f, "`{}` exited with status {}: {}", command, code, stderr.trim()),
+                None => write!(f, "`{}` was terminated by a signal: {}", command, stderr.trim()),
+            },
+            Self::Parse(reason) => write!(f, "unable to parse wireguard output: {}", reason),
+            Self::InvalidKey(key) => write!(f, "invalid wireguard key '{}'", key),
+            Self::UnknownInterface(name) => write!(f, "unknown interface '{}'", name),
+            Self::UnknownPeer(key) => write!(f, "unknown peer '{}'", key),
+            Self::PeerDisconnected(key) => write!(f, "peer '{}' is not connected to a slot", key),
+            Self::Netlink(reason) => write!(f, "netlink: {}", reason),
+            Self::UnsupportedBackend(name) => write!(f, "unsupported wireguard backend '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for WireGuardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Spawn { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+// Selects a backend by the name given in configuration (`wireguard_backend`).
+pub fn backend_from_name(name: &str) -> Result<Backend, WireGuardError> {
+    match name.to_lowercase().as_str() {
+        "cli" | "" => Ok(Arc::new(CliBackend::new())),
+        "memory" => Ok(Arc::new(MemoryBackend::new())),
+        #[cfg(feature = "netlink")]
+        "netlink" => Ok(Arc::new(super::NetlinkBackend::new())),
+        other => Err(WireGuardError::UnsupportedBackend(other.to_string())),
+    }
+}
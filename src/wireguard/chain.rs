@@ -0,0 +1,396 @@
+use crate::types::{Client, Connection, ExitNode, ExitRoute, PresharedKey, RekeyNotice, ServerMessage, decode_key, with_tunnel};
+use futures::{SinkExt, StreamExt};
+use futures_timer::Delay;
+use ipnet::IpNet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::WireGuardConfig;
+
+// How long an exit node is given to answer the `open` query.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+// A session with an exit node which ended is reopened up to this many times, waiting twice as long after each attempt.
+const REOPEN_ATTEMPTS: u32 = 5;
+const REOPEN_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    Disabled,
+    UnknownExit(String),
+    InvalidKey(String),
+    Connect(String),
+    Rejected(String),
+    InvalidReply(String)
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "chaining is not configured on this node"),
+            Self::UnknownExit(name) => write!(f, "unknown exit node {}", name),
+            Self::InvalidKey(reason) => write!(f, "invalid exit_public_key, {}", reason),
+            Self::Connect(reason) => write!(f, "unable to reach exit node, {}", reason),
+            Self::Rejected(reason) => write!(f, "exit node refused the session, {}", reason),
+            Self::InvalidReply(reason) => write!(f, "exit node sent an invalid reply, {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+pub type ExitSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+// What it takes to open a session with an exit node for a clients inner peer, read from the configuration
+// so the session can be opened without holding it, see `open_exit_session`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitRequest {
+    pub exit: ExitNode,
+    pub author: String,
+    // The inner peers public key, the exit node never learns the clients own.
+    pub public_key: String
+}
+
+// A session the exit node accepted for a clients inner peer.
+pub struct ExitSession {
+    pub request: ExitRequest,
+    pub route: ExitRoute,
+    pub endpoint: SocketAddr,
+    pub socket: ExitSocket
+}
+
+// The session a chained client holds with its exit node through this node, see `hold_exit_session`.
+#[derive(Clone)]
+pub struct ChainedSession {
+    pub route: ExitRoute,
+    pub endpoint: SocketAddr,
+    task: Arc<JoinHandle<()>>
+}
+
+impl ChainedSession {
+    // Dropping the socket closes the session, the exit node leaves its usage to this node.
+    pub fn close(&self) {
+        self.task.abort();
+    }
+}
+
+impl WireGuardConfig {
+    pub fn exit_request(&self, exit: &str, public_key: Option<&str>) -> Result<ExitRequest, ChainError> {
+        let exit = match self.config.chain.exit_node(exit) {
+            Some(exit) => exit.clone(),
+            None => return Err(ChainError::UnknownExit(exit.to_string()))
+        };
+
+        let author = match &self.config.chain.author {
+            Some(author) => author.clone(),
+            None => return Err(ChainError::Disabled)
+        };
+
+        let public_key = match public_key {
+            Some(public_key) => public_key.trim().to_string(),
+            None => return Err(ChainError::InvalidKey("it is required with exit_node".to_string()))
+        };
+
+        if let Err(err) = decode_key(&public_key) {
+            return Err(ChainError::InvalidKey(err.to_string()));
+        }
+
+        Ok(ExitRequest { exit, author, public_key })
+    }
+
+    // What a chained client should set as AllowedIPs on its outer peer, the exit nodes endpoint (which its
+    // inner peer dials) and the interfaces own networks. Everything else is carried by the inner peer.
+    pub fn chained_allowed_ips(&self, client: &Client, endpoint: SocketAddr) -> Vec<IpNet> {
+        let networks = match &client.connected {
+            Connection::Connected(connection) => self.interface(&connection.interface).map(| interface | interface.networks()).unwrap_or_default(),
+            Connection::Disconnected => vec![]
+        };
+
+        with_tunnel(&[IpNet::from(endpoint.ip())], &networks)
+    }
+
+    // Keeps `task`, holding the session of `client_id` with its exit node, until the client closes its session.
+    pub fn attach_exit_session(&mut self, client_id: &str, route: ExitRoute, endpoint: SocketAddr, task: JoinHandle<()>) {
+        let session = ChainedSession { route, endpoint, task: Arc::new(task) };
+
+        if let Some(previous) = self.chained.insert(client_id.to_string(), session) {
+            previous.close();
+        }
+    }
+
+    // The session with the exit node was reopened, so its route may have changed.
+    pub fn update_exit_session(&mut self, client_id: &str, route: ExitRoute, endpoint: SocketAddr) -> bool {
+        match self.chained.get_mut(client_id) {
+            Some(session) => {
+                session.route = route;
+                session.endpoint = endpoint;
+                true
+            },
+            None => false
+        }
+    }
+
+    // The session is left running, so it can be closed by the caller or end on its own.
+    pub fn detach_exit_session(&mut self, client_id: &str) -> Option<ChainedSession> {
+        self.chained.remove(client_id)
+    }
+}
+
+// Opens a session with the exit node for the inner peer, as the client would itself. This waits on the exit
+// node (up to `OPEN_TIMEOUT`) and DNS, so it is never called with the configuration or clients locked.
+pub async fn open_exit_session(request: ExitRequest) -> Result<ExitSession, ChainError> {
+    let url = reqwest::Url::parse_with_params(&request.exit.url, &[("public_key", request.public_key.as_str()), ("author", request.author.as_str())])
+        .map_err(| err | ChainError::Connect(err.to_string()))?;
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str()).await
+        .map_err(| err | ChainError::Connect(err.to_string()))?;
+
+    socket.send(Message::Text("{ \"query_type\": \"open\" }".to_string())).await
+        .map_err(| err | ChainError::Connect(err.to_string()))?;
+
+    let mut route = match tokio::time::timeout(OPEN_TIMEOUT, read_open_reply(&mut socket)).await {
+        Ok(route) => route?,
+        Err(_) => return Err(ChainError::Connect(format!("no reply within {}s", OPEN_TIMEOUT.as_secs())))
+    };
+
+    // Resolved here, as the client routes the address (not the name) through its outer peer.
+    let endpoint = match tokio::net::lookup_host(&route.endpoint).await.ok().and_then(| mut addresses | addresses.next()) {
+        Some(endpoint) => endpoint,
+        None => return Err(ChainError::InvalidReply(format!("unresolvable endpoint {}", route.endpoint)))
+    };
+
+    route.name = request.exit.name.clone();
+    route.endpoint = endpoint.to_string();
+
+    Ok(ExitSession { request, route, endpoint, socket })
+}
+
+// As `open_exit_session`, retried with a backoff for a session which ended.
+pub async fn reopen_exit_session(request: &ExitRequest) -> Result<ExitSession, ChainError> {
+    let mut backoff = REOPEN_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        Delay::new(backoff).await;
+
+        match open_exit_session(request.clone()).await {
+            Ok(session) => return Ok(session),
+            Err(err) if attempt >= REOPEN_ATTEMPTS => return Err(err),
+            Err(err) => println!("[chain]: Unable to reopen session with exit {} ({}/{}), {}", request.exit.name, attempt, REOPEN_ATTEMPTS, err)
+        }
+
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn read_open_reply(socket: &mut ExitSocket) -> Result<ExitRoute, ChainError> {
+    while let Some(message) = socket.next().await {
+        match message {
+            Ok(Message::Text(text)) => {
+                if let Some(route) = parse_open_reply(&text)? {
+                    return Ok(route);
+                }
+            },
+            Ok(_) => continue,
+            Err(err) => return Err(ChainError::Connect(err.to_string()))
+        }
+    }
+
+    Err(ChainError::Connect("session closed before the open reply".to_string()))
+}
+
+// None for any message but the `open` reply, or an error.
+fn parse_open_reply(text: &str) -> Result<Option<ExitRoute>, ChainError> {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => return Ok(None)
+    };
+
+    match value["type"].as_str() {
+        Some("error") => Err(ChainError::Rejected(value["message"].to_string())),
+        // Other messages (i.e. PUBLIC_KEY_OK) carry a plain string.
+        Some("message") if value["message"].is_object() => {
+            let route = serde_json::from_value::<ExitRoute>(value["message"].clone())
+                .map_err(| err | ChainError::InvalidReply(err.to_string()))?;
+
+            decode_key(&route.server_public_key)
+                .map_err(| err | ChainError::InvalidReply(format!("server_public_key, {}", err)))?;
+
+            if let Some(key) = &route.preshared_key {
+                PresharedKey::from_encoded(key).map_err(| err | ChainError::InvalidReply(format!("preshared_key, {}", err)))?;
+            }
+
+            Ok(Some(route))
+        },
+        _ => Ok(None)
+    }
+}
+
+// The messages of an exit node its chained client has to act on, its key rotations. The rest (i.e. usage
+// updates of the inner peer) are not relayed.
+pub fn relayed_message(text: &str) -> Option<ServerMessage> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+
+    let notice = RekeyNotice {
+        server_public_key: value["message"]["server_public_key"].as_str()?.to_string(),
+        deadline: value["message"]["deadline"].as_str().unwrap_or_default().to_string()
+    };
+
+    match value["type"].as_str()? {
+        "rekey" => Some(ServerMessage::ExitRekey(notice)),
+        "rekey_complete" => Some(ServerMessage::ExitRekeyComplete(notice)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::KeyState;
+    use crate::wireguard::testing::{INTERFACE, connect, node_with};
+
+    const CHAIN: &str = "
+chain_author: entry
+chain_exit_nodes:
+  - name: exit
+    url: wss://exit.example/ws
+";
+
+    fn route() -> ExitRoute {
+        ExitRoute {
+            name: "exit".to_string(),
+            server_public_key: KeyState::generate_pair().public_key,
+            endpoint: "198.51.100.1:8443".to_string(),
+            address: "10.8.0.1".to_string(),
+            address_v6: None,
+            preshared_key: None,
+            dns: vec![]
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_exit_sessions_for_the_inner_key() {
+        let (config, _) = node_with(CHAIN);
+        let inner = KeyState::generate_pair().public_key;
+
+        let request = config.exit_request("exit", Some(&inner)).unwrap();
+
+        assert_eq!(request.exit.url, "wss://exit.example/ws");
+        assert_eq!(request.author, "entry");
+        assert_eq!(request.public_key, inner);
+    }
+
+    #[tokio::test]
+    async fn refuses_exit_sessions_it_cannot_open() {
+        let (config, _) = node_with(CHAIN);
+        let inner = KeyState::generate_pair().public_key;
+
+        assert_eq!(config.exit_request("elsewhere", Some(&inner)), Err(ChainError::UnknownExit("elsewhere".to_string())));
+        assert!(matches!(config.exit_request("exit", None), Err(ChainError::InvalidKey(_))));
+        assert!(matches!(config.exit_request("exit", Some("not-a-key")), Err(ChainError::InvalidKey(_))));
+
+        let (config, _) = node_with("
+chain_exit_nodes:
+  - name: exit
+    url: wss://exit.example/ws
+");
+
+        assert_eq!(config.exit_request("exit", Some(&inner)), Err(ChainError::Disabled));
+    }
+
+    #[test]
+    fn parses_the_open_reply() {
+        assert_eq!(parse_open_reply("{ \"message\": \"PUBLIC_KEY_OK\", \"type\": \"message\" }"), Ok(None));
+        assert_eq!(parse_open_reply("{ \"message\": { \"up\": 1, \"down\": 2 }, \"type\": \"update\" }"), Ok(None));
+        assert_eq!(parse_open_reply("{ \"message\": \"Server is shutting down.\", \"type\": \"error\" }"), Err(ChainError::Rejected("\"Server is shutting down.\"".to_string())));
+
+        let mut expected = route();
+        expected.name = String::new();
+        expected.preshared_key = Some(PresharedKey::generate().as_str().to_string());
+        expected.dns = vec!["10.8.2.1".to_string()];
+
+        let reply = serde_json::json!({ "message": expected, "type": "message" }).to_string();
+        assert_eq!(parse_open_reply(&reply), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn rejects_open_replies_with_invalid_keys() {
+        let mut invalid = route();
+        invalid.server_public_key = "short".to_string();
+
+        let reply = serde_json::json!({ "message": invalid, "type": "message" }).to_string();
+        assert!(matches!(parse_open_reply(&reply), Err(ChainError::InvalidReply(_))));
+
+        let mut invalid = route();
+        invalid.preshared_key = Some("!!".to_string());
+
+        let reply = serde_json::json!({ "message": invalid, "type": "message" }).to_string();
+        assert!(matches!(parse_open_reply(&reply), Err(ChainError::InvalidReply(_))));
+    }
+
+    #[test]
+    fn relays_only_key_rotations() {
+        let rekey = "{ \"message\": { \"server_public_key\": \"key\", \"deadline\": \"2026-10-18T00:00:00+00:00\" }, \"type\": \"rekey\" }";
+
+        match relayed_message(rekey) {
+            Some(ServerMessage::ExitRekey(notice)) => {
+                assert_eq!(notice.server_public_key, "key");
+                assert_eq!(notice.deadline, "2026-10-18T00:00:00+00:00");
+            },
+            message => panic!("unexpected {:?}", message)
+        }
+
+        let complete = "{ \"message\": { \"server_public_key\": \"key\", \"deadline\": \"2026-10-18T00:00:00+00:00\" }, \"type\": \"rekey_complete\" }";
+        assert!(matches!(relayed_message(complete), Some(ServerMessage::ExitRekeyComplete(_))));
+
+        assert!(relayed_message("{ \"message\": { \"up\": 1, \"down\": 2 }, \"type\": \"update\" }").is_none());
+        assert!(relayed_message("{ \"message\": \"Removed client successfully.\", \"type\": \"message\" }").is_none());
+    }
+
+    #[tokio::test]
+    async fn routes_only_the_exit_endpoint_through_the_outer_peer() {
+        let (mut config, _) = node_with(CHAIN);
+        let client = connect(&mut config, "client");
+
+        let allowed_ips = config.chained_allowed_ips(&client, "198.51.100.1:8443".parse().unwrap());
+        let allowed_ips = allowed_ips.iter().map(| network | network.to_string()).collect::<Vec<String>>();
+
+        assert!(allowed_ips.contains(&"198.51.100.1/32".to_string()));
+        assert!(allowed_ips.contains(&"10.8.0.0/16".to_string()));
+        assert!(!allowed_ips.contains(&"0.0.0.0/0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn attaches_and_detaches_exit_sessions() {
+        let (mut config, backend) = node_with(CHAIN);
+        config.config_up().await.unwrap();
+
+        let client = connect(&mut config, "client");
+        config.add_peer(&client).await.unwrap();
+
+        let endpoint: SocketAddr = "198.51.100.1:8443".parse().unwrap();
+        let task = tokio::spawn(futures::future::pending::<()>());
+
+        config.attach_exit_session("client", route(), endpoint, task);
+        assert_eq!(config.chained["client"].endpoint, endpoint);
+
+        let moved: SocketAddr = "198.51.100.2:8443".parse().unwrap();
+        assert!(config.update_exit_session("client", route(), moved));
+        assert!(!config.update_exit_session("other", route(), moved));
+
+        let session = config.detach_exit_session("client").unwrap();
+        assert_eq!(session.endpoint, moved);
+        assert!(config.chained.is_empty());
+
+        session.close();
+
+        // The outer peer is an ordinary one, the entry node holds no peer of its own with the exit node.
+        let peers = backend.interface(INTERFACE).unwrap().peers;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers["client"].config.allowed_ips[0], "10.8.0.1/32");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::types::PeerStats;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use super::{InterfaceSettings, PeerConfig, WireGuardBackend, WireGuardError, parse_dump};
+
+// Drives the interface through `wg` and `wg-quick`, as the node always has.
+// `wg-quick` reads `/etc/wireguard/<interface>.conf`, which `WireGuardConfig::save_config` writes before bringing it up.
+pub struct CliBackend;
+
+impl CliBackend {
+    pub fn new() -> Self {
+        CliBackend
+    }
+
+    fn run(program: &str, args: &[&str]) -> Result<Output, WireGuardError> {
+        CliBackend::run_with_input(program, args, None)
+    }
+
+    // Secrets are handed over on stdin (as `/dev/stdin`) so they never appear in the process list.
+    fn run_with_input(program: &str, args: &[&str], input: Option<&str>) -> Result<Output, WireGuardError> {
+        let command = format!("{} {}", program, args.join(" "));
+
+        let mut child = match Command::new(program)
+            .env("WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")
+            .args(args)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn() {
+                Ok(child) => child,
+                Err(source) => return Err(WireGuardError::Spawn { command, source })
+        };
+
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            if let Err(source) = stdin.write_all(input.as_bytes()) {
+                return Err(WireGuardError::Spawn { command, source });
+            }
+            // Dropping stdin closes it, letting the process see EOF.
+        }
+
+        let output = match child.wait_with_output() {
+            Ok(output) => output,
+            Err(source) => return Err(WireGuardError::Spawn { command, source })
+        };
+
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(WireGuardError::Command {
+                command,
+                status: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string()
+            })
+        }
+    }
+
+    fn stdout(output: Output) -> Result<String, WireGuardError> {
+        match String::from_utf8(output.stdout) {
+            Ok(string) => Ok(string),
+            Err(err) => Err(WireGuardError::Parse(format!("output was not valid UTF8, {}", err)))
+        }
+    }
+
+    fn set_peer(interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        let allowed_ips = peer.allowed_ips.join(",");
+        let keepalive = peer.persistent_keepalive.unwrap_or(0).to_string();
+
+        let mut args = vec![
+            "set", interface, "peer", &peer.public_key,
+            "allowed-ips", &allowed_ips,
+            "persistent-keepalive", &keepalive
+        ];
+
+        match &peer.preshared_key {
+            Some(preshared_key) => {
+                args.extend(["preshared-key", "/dev/stdin"]);
+                CliBackend::run_with_input("wg", &args, Some(preshared_key.as_str()))?;
+            },
+            None => {
+                CliBackend::run("wg", &args)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl WireGuardBackend for CliBackend {
+    fn name(&self) -> &'static str {
+        "cli"
+    }
+
+    fn interface_up(&self, interface: &InterfaceSettings) -> Result<(), WireGuardError> {
+        CliBackend::run("wg-quick", &["up", &interface.name])?;
+        Ok(())
+    }
+
+    fn interface_down(&self, interface: &str) -> Result<(), WireGuardError> {
+        CliBackend::run("wg-quick", &["down", interface])?;
+        Ok(())
+    }
+
+    fn set_private_key(&self, interface: &str, private_key: &str) -> Result<(), WireGuardError> {
+        CliBackend::run_with_input("wg", &["set", interface, "private-key", "/dev/stdin"], Some(private_key.trim()))?;
+        Ok(())
+    }
+
+    fn add_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        CliBackend::set_peer(interface, peer)
+    }
+
+    fn update_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        // `wg set` replaces the allowed-ips and keepalive of an existing peer in place.
+        CliBackend::set_peer(interface, peer)
+    }
+
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WireGuardError> {
+        CliBackend::run("wg", &["set", interface, "peer", public_key, "remove"])?;
+        Ok(())
+    }
+
+    fn dump(&self, interface: &str) -> Result<Vec<PeerStats>, WireGuardError> {
+        Ok(parse_dump(&CliBackend::stdout(CliBackend::run("wg", &["show", interface, "dump"])?)?))
+    }
+}
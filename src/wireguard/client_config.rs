@@ -0,0 +1,67 @@
+use crate::types::{Client, Connection, Key, KeyState, Section, WgQuickConfig};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{rngs::OsRng, RngCore};
+
+use super::WireGuardConfig;
+
+// Handed out with the open reply, anyone holding it can fetch the sessions config until it is closed.
+pub fn generate_config_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Whether `private_key` belongs to the clients session, so a config is never rendered with someone elses key.
+pub fn is_key_of(client: &Client, private_key: &str) -> bool {
+    matches!(KeyState::from_private_key(private_key), Ok(keys) if keys.public_key == client.public_key)
+}
+
+impl WireGuardConfig {
+    // The wg-quick config a connected client can bring its session up with, in any WireGuard app. Without
+    // `private_key` the PrivateKey is left out for the client to fill in, the node never learns it otherwise.
+    pub fn client_config(&self, client: &Client, private_key: Option<&str>) -> Option<WgQuickConfig> {
+        let host = match &client.connected {
+            Connection::Connected(host) => host,
+            Connection::Disconnected => return None
+        };
+
+        let interface = self.interface(&host.interface)?;
+
+        let mut addresses = vec![format!("{}/32", interface.host_ipv4(host))];
+
+        if let Some(address) = interface.host_ipv6(host) {
+            addresses.push(format!("{}/128", address));
+        }
+
+        let mut config = WgQuickConfig::new();
+
+        if let Some(private_key) = private_key {
+            config.interface.set(Key::PrivateKey, private_key.trim());
+        }
+
+        config.interface
+            .set(Key::Address, addresses.join(", "))
+            .set(Key::Dns, self.client_dns(interface).join(", "))
+            .set(Key::Mtu, self.config.client_mtu.to_string());
+
+        let mut peer = Section::default();
+
+        peer.set(Key::PublicKey, self.keys.public_key.trim());
+
+        if let Some(preshared_key) = &client.preshared_key {
+            peer.set(Key::PresharedKey, preshared_key.as_str());
+        }
+
+        peer.set(Key::AllowedIps, client.allowed_ips.iter().map(| network | network.to_string()).collect::<Vec<String>>().join(", "))
+            .set(Key::Endpoint, format!("{}:{}", self.config.address, interface.listen_port));
+
+        if self.config.client_keepalive > 0 {
+            peer.set(Key::PersistentKeepalive, self.config.client_keepalive.to_string());
+        }
+
+        config.peers.push(peer);
+
+        Some(config)
+    }
+}
@@ -0,0 +1,151 @@
+use crate::types::PeerStats;
+use chrono::{TimeZone, Utc};
+use std::net::SocketAddr;
+
+use super::WireGuardError;
+
+// Parses the output of `wg show <interface> dump`. The first line describes the interface
+// (private key, public key, listen port, fwmark), every following line is a tab separated peer:
+// public key, preshared key, endpoint, allowed ips, latest handshake, rx, tx, persistent keepalive.
+// A peer line which cannot be parsed is logged and skipped, so the remaining peers are still accounted.
+pub fn parse_dump(output: &str) -> Vec<PeerStats> {
+    output.lines()
+        .skip(1)
+        .map(| line | line.trim())
+        .filter(| line | !line.is_empty())
+        .filter_map(| line | match parse_peer_line(line) {
+            Ok(stats) => Some(stats),
+            Err(err) => {
+                println!("[err]: Skipping unreadable peer in dump, {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn parse_peer_line(line: &str) -> Result<PeerStats, WireGuardError> {
+    let columns: Vec<&str> = line.split('\t').collect();
+
+    if columns.len() != 8 {
+        return Err(WireGuardError::Parse(format!("expected 8 columns in peer line, found {}", columns.len())));
+    }
+
+    let public_key = columns[0].to_string();
+
+    let endpoint = match columns[2] {
+        "(none)" => None,
+        endpoint => Some(endpoint.parse::<SocketAddr>()
+            .map_err(| err | WireGuardError::Parse(format!("invalid endpoint '{}' for {}, {}", endpoint, public_key, err)))?)
+    };
+
+    let allowed_ips = match columns[3] {
+        "(none)" => vec![],
+        ips => ips.split(',').map(| ip | ip.to_string()).collect()
+    };
+
+    let latest_handshake = match parse_number::<i64>(columns[4], "latest handshake", &public_key)? {
+        // Peers which have never completed a handshake report 0.
+        0 => None,
+        seconds => Some(Utc.timestamp_opt(seconds, 0).single()
+            .ok_or_else(|| WireGuardError::Parse(format!("latest handshake {} out of range for {}", seconds, public_key)))?)
+    };
+
+    let rx = parse_number::<u64>(columns[5], "rx", &public_key)?;
+    let tx = parse_number::<u64>(columns[6], "tx", &public_key)?;
+
+    let persistent_keepalive = match columns[7] {
+        "off" => None,
+        keepalive => Some(parse_number::<u16>(keepalive, "persistent keepalive", &public_key)?)
+    };
+
+    Ok(PeerStats {
+        public_key,
+        endpoint,
+        allowed_ips,
+        latest_handshake,
+        rx,
+        tx,
+        persistent_keepalive
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, column: &str, public_key: &str) -> Result<T, WireGuardError>
+where
+    T::Err: std::fmt::Display
+{
+    value.parse::<T>()
+        .map_err(| err | WireGuardError::Parse(format!("invalid {} '{}' for {}, {}", column, value, public_key, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERFACE: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\tHIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=\t51820\toff";
+
+    #[test]
+    fn parses_a_connected_peer() {
+        let line = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\t(none)\t192.0.2.1:51820\t10.8.2.2/32,fd10:8::2:2/128\t1700000000\t1024\t2048\t25";
+        let stats = parse_peer_line(line).unwrap();
+
+        assert_eq!(stats.public_key, "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=");
+        assert_eq!(stats.endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(stats.allowed_ips, vec!["10.8.2.2/32", "fd10:8::2:2/128"]);
+        assert_eq!(stats.latest_handshake.map(| handshake | handshake.timestamp()), Some(1700000000));
+        assert_eq!((stats.rx, stats.tx), (1024, 2048));
+        assert_eq!(stats.persistent_keepalive, Some(25));
+    }
+
+    #[test]
+    fn parses_a_peer_which_never_connected() {
+        let line = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\t(none)\t(none)\t(none)\t0\t0\t0\toff";
+        let stats = parse_peer_line(line).unwrap();
+
+        assert_eq!(stats.endpoint, None);
+        assert!(stats.allowed_ips.is_empty());
+        assert_eq!(stats.latest_handshake, None);
+        assert_eq!(stats.persistent_keepalive, None);
+    }
+
+    #[test]
+    fn parses_ipv6_endpoints() {
+        let line = "key\t(none)\t[2001:db8::1]:51820\t10.8.2.2/32\t1\t0\t0\toff";
+
+        assert_eq!(parse_peer_line(line).unwrap().endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_malformed_peer_lines() {
+        for line in [
+            "key\t(none)\t(none)",
+            "key\t(none)\tnot-an-endpoint\t(none)\t0\t0\t0\toff",
+            "key\t(none)\t(none)\t(none)\tnever\t0\t0\toff",
+            "key\t(none)\t(none)\t(none)\t0\t-1\t0\toff",
+            "key\t(none)\t(none)\t(none)\t0\t0\t0\talways"
+        ] {
+            assert!(parse_peer_line(line).is_err(), "{:?} should not parse", line);
+        }
+    }
+
+    #[test]
+    fn skips_the_interface_line_and_bad_peers() {
+        let dump = [
+            INTERFACE,
+            "a\t(none)\t(none)\t10.8.2.2/32\t0\t10\t20\toff",
+            "b\t(none)\tgarbage\t10.8.2.3/32\t0\t0\t0\toff",
+            "",
+            "c\t(none)\t(none)\t10.8.2.4/32\t0\t30\t40\t25"
+        ].join("\n");
+
+        let peers = parse_dump(&dump);
+
+        assert_eq!(peers.iter().map(| peer | peer.public_key.as_str()).collect::<Vec<&str>>(), vec!["a", "c"]);
+        assert_eq!(peers[1].rx, 30);
+    }
+
+    #[test]
+    fn parses_an_interface_without_peers() {
+        assert!(parse_dump(INTERFACE).is_empty());
+        assert!(parse_dump("").is_empty());
+    }
+}
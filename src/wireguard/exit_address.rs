@@ -0,0 +1,144 @@
+use crate::types::{Client, Connection, ExitAddressPolicy};
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+use super::{WireGuardConfig, WireGuardError};
+
+// Which exit address each session was given.
+#[derive(Debug, Clone, Default)]
+pub struct ExitAssignments {
+    // Next shared address for round-robin.
+    next: usize,
+    // Dedicated addresses in use, with the account holding them and the sessions using them.
+    dedicated: HashMap<Ipv4Addr, (String, HashSet<String>)>
+}
+
+// FNV-1a, as the assignment of an author must not change between restarts (or Rust versions).
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, | hash, byte | (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl WireGuardConfig {
+    // Picks the exit address of a connected session and points its traffic at it. None when the node has
+    // no exit addresses, peers are then masqueraded behind the egress interfaces address.
+    pub fn assign_exit_address(&mut self, client: &Client) -> Result<Option<Ipv4Addr>, WireGuardError> {
+        if !self.config.exit_addresses.is_enabled() {
+            return Ok(None);
+        }
+
+        let (interface, source) = match &client.connected {
+            Connection::Connected(host) => match self.interface(&host.interface) {
+                Some(interface) => (interface.name.clone(), interface.host_ipv4(host)),
+                None => return Ok(None)
+            },
+            Connection::Disconnected => return Ok(None)
+        };
+
+        let exit = match self.dedicated_exit_address(client) {
+            Some(exit) => exit,
+            None => self.shared_exit_address(client)
+        };
+
+        if let Err(err) = self.firewall.snat_peer(&interface, source, exit) {
+            self.release_dedicated(exit, &client.public_key);
+            return Err(err);
+        }
+
+        println!("[egress]: {} ({}) exits through {}", client.public_key, client.author, exit);
+
+        Ok(Some(exit))
+    }
+
+    pub fn release_exit_address(&mut self, client: &Client) {
+        let exit = match client.exit_address {
+            Some(exit) => exit,
+            None => return
+        };
+
+        if let Connection::Connected(host) = &client.connected {
+            if let Some(interface) = self.interface(&host.interface) {
+                if let Err(err) = self.firewall.unsnat_peer(&interface.name, interface.host_ipv4(host), exit) {
+                    println!("[err]: Unable to remove exit address of {}, {}", client.public_key, err);
+                }
+            }
+        }
+
+        self.release_dedicated(exit, &client.public_key);
+    }
+
+    // A session still open from an earlier connection keeps the address it was given then. Should its account
+    // have become PRO since, it is moved to a dedicated address once its tier is set, returning the new address.
+    pub fn reassign_exit_address(&mut self, client: &mut Client) -> Option<Ipv4Addr> {
+        if client.tier() != "PRO" || client.exit_node.is_some() || matches!(client.connected, Connection::Disconnected) {
+            return None;
+        }
+
+        let current = client.exit_address?;
+
+        let has_dedicated = self.exit_assignments.dedicated.contains_key(&current);
+        let dedicated_available = self.exit_assignments.dedicated.values().any(| (author, _) | *author == client.author)
+            || self.config.exit_addresses.dedicated.iter().any(| exit | !self.exit_assignments.dedicated.contains_key(exit));
+
+        if has_dedicated || !dedicated_available {
+            return None;
+        }
+
+        self.release_exit_address(client);
+        client.exit_address = None;
+
+        match self.assign_exit_address(client) {
+            Ok(exit_address) => client.exit_address = exit_address,
+            Err(err) => println!("[err]: Unable to reassign the exit address of {}, {}", client.public_key, err)
+        }
+
+        client.exit_address
+    }
+
+    // PRO accounts keep a dedicated address for as long as any of their sessions is open.
+    fn dedicated_exit_address(&mut self, client: &Client) -> Option<Ipv4Addr> {
+        if client.tier() != "PRO" {
+            return None;
+        }
+
+        let held = self.exit_assignments.dedicated.iter()
+            .find(| (_, (author, _)) | *author == client.author)
+            .map(| (exit, _) | *exit);
+
+        let exit = match held {
+            Some(exit) => exit,
+            None => *self.config.exit_addresses.dedicated.iter()
+                .find(| exit | !self.exit_assignments.dedicated.contains_key(*exit))?
+        };
+
+        self.exit_assignments.dedicated.entry(exit)
+            .or_insert_with(|| (client.author.clone(), HashSet::new()))
+            .1.insert(client.public_key.clone());
+
+        Some(exit)
+    }
+
+    fn shared_exit_address(&mut self, client: &Client) -> Ipv4Addr {
+        let shared = &self.config.exit_addresses.shared;
+
+        let index = match self.config.exit_addresses.policy {
+            ExitAddressPolicy::RoundRobin => {
+                let index = self.exit_assignments.next % shared.len();
+                self.exit_assignments.next = index + 1;
+                index
+            },
+            ExitAddressPolicy::StickyPerAuthor => (stable_hash(&client.author) % shared.len() as u64) as usize
+        };
+
+        shared[index]
+    }
+
+    fn release_dedicated(&mut self, exit: Ipv4Addr, public_key: &str) {
+        if let Some((_, sessions)) = self.exit_assignments.dedicated.get_mut(&exit) {
+            sessions.remove(public_key);
+
+            if sessions.is_empty() {
+                self.exit_assignments.dedicated.remove(&exit);
+            }
+        }
+    }
+}
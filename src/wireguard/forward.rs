@@ -0,0 +1,105 @@
+use crate::types::{Client, Connection, PortForward};
+use std::fmt;
+
+use super::{WireGuardConfig, WireGuardError};
+
+#[derive(Debug)]
+pub enum ForwardError {
+    Disabled,
+    NotConnected,
+    // The tiers limit, which may be 0.
+    LimitReached(usize),
+    Exhausted,
+    Firewall(WireGuardError)
+}
+
+impl fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "port forwarding is not enabled on this node"),
+            Self::NotConnected => write!(f, "ports can only be forwarded to a connected session"),
+            Self::LimitReached(0) => write!(f, "port forwarding is not available on your tier"),
+            Self::LimitReached(limit) => write!(f, "your tier allows at most {} forwarded ports", limit),
+            Self::Exhausted => write!(f, "no ports are free on this node"),
+            Self::Firewall(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for ForwardError {}
+
+impl From<WireGuardError> for ForwardError {
+    fn from(err: WireGuardError) -> Self {
+        ForwardError::Firewall(err)
+    }
+}
+
+// Forwarded ports are held in `forwards` next to the slot registry, keyed by port. A forward belongs to the
+// session it was made for, it is released with the session (see `close_query`) and the reconciler releases
+// any whose session has gone.
+impl WireGuardConfig {
+    pub fn forward_port(&mut self, client: &Client) -> Result<PortForward, ForwardError> {
+        let range = match self.config.port_forward_range {
+            Some(range) => range,
+            None => return Err(ForwardError::Disabled)
+        };
+
+        let host = match &client.connected {
+            Connection::Connected(host) => host,
+            Connection::Disconnected => return Err(ForwardError::NotConnected)
+        };
+
+        let limit = client.maximums.port_forwards();
+
+        if self.forwards_of(&client.public_key).len() >= limit {
+            return Err(ForwardError::LimitReached(limit));
+        }
+
+        let port = match range.ports().find(| port | !self.forwards.contains_key(port)) {
+            Some(port) => port,
+            None => return Err(ForwardError::Exhausted)
+        };
+
+        let address = match self.interface(&host.interface) {
+            Some(interface) => interface.host_ipv4(host),
+            None => return Err(ForwardError::NotConnected)
+        };
+
+        let forward = PortForward {
+            port,
+            owner: client.public_key.clone(),
+            interface: host.interface.clone(),
+            address
+        };
+
+        self.firewall.forward_port(&forward)?;
+        self.forwards.insert(port, forward.clone());
+
+        println!("[forward]: Forwarded port {} to {} ({})", port, address, client.public_key);
+
+        Ok(forward)
+    }
+
+    pub fn forwards_of(&self, public_key: &str) -> Vec<PortForward> {
+        self.forwards.values()
+            .filter(| forward | forward.owner == public_key)
+            .cloned()
+            .collect()
+    }
+
+    // Releases every port forwarded to the client, returning the released ports.
+    pub fn release_ports(&mut self, public_key: &str) -> Vec<u16> {
+        let released = self.forwards_of(public_key);
+
+        for forward in &released {
+            if let Err(err) = self.firewall.unforward_port(forward) {
+                println!("[err]: Unable to remove forward of port {}, {}", forward.port, err);
+            }
+
+            self.forwards.remove(&forward.port);
+            println!("[forward]: Released port {} of {}", forward.port, public_key);
+        }
+
+        released.iter().map(| forward | forward.port).collect()
+    }
+}
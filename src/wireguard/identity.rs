@@ -0,0 +1,217 @@
+use crate::types::{Key, KeyState, WireGuardConfigFile};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const IDENTITY_FILE: &str = "server.key";
+
+#[derive(Debug, Clone)]
+pub enum IdentitySource {
+    Configuration,
+    SecretFile(PathBuf),
+    Imported(PathBuf),
+    StateDirectory(PathBuf),
+    Generated(PathBuf)
+}
+
+impl fmt::Display for IdentitySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Configuration => write!(f, "private_key"),
+            Self::SecretFile(path) => write!(f, "private_key_file {}", path.display()),
+            Self::Imported(path) => write!(f, "import_config {}", path.display()),
+            Self::StateDirectory(path) => write!(f, "{}", path.display()),
+            Self::Generated(path) => write!(f, "a new key persisted to {}", path.display())
+        }
+    }
+}
+
+// Resolves the servers key pair, in order of precedence:
+//  1. `private_key` given directly in configuration,
+//  2. `private_key_file`, i.e. a mounted kubernetes secret,
+//  3. the PrivateKey of the wg-quick file given as `import_config`,
+//  4. the key persisted in `state_directory` by a previous boot,
+//  5. a freshly generated key, which is then persisted to `state_directory`.
+// Keeping the same key across restarts means the `server_public_key` clients were given stays valid.
+pub fn load_identity(config: &WireGuardConfigFile) -> (KeyState, IdentitySource) {
+    if let Some(private_key) = &config.private_key {
+        match KeyState::from_private_key(private_key) {
+            Ok(keys) => return (keys, IdentitySource::Configuration),
+            Err(err) => panic!("[err]: Configured private_key is invalid. Reason: {}", err)
+        }
+    }
+
+    if let Some(path) = &config.private_key_file {
+        let path = PathBuf::from(path);
+
+        match read_key(&path) {
+            Ok(keys) => return (keys, IdentitySource::SecretFile(path)),
+            Err(err) => panic!("[err]: Unable to load private_key_file {:?}. Reason: {}", path, err)
+        }
+    }
+
+    if let (Some(path), Some(private_key)) = (&config.import_config, imported_key(config)) {
+        match KeyState::from_private_key(private_key) {
+            Ok(keys) => return (keys, IdentitySource::Imported(PathBuf::from(path))),
+            Err(err) => panic!("[err]: PrivateKey of import_config {} is invalid. Reason: {}", path, err)
+        }
+    }
+
+    let path = identity_path(&config.state_directory);
+
+    if path.exists() {
+        match read_key(&path) {
+            Ok(keys) => return (keys, IdentitySource::StateDirectory(path)),
+            Err(err) => {
+                // Never silently overwrite what may be an operators key, set it aside instead.
+                let invalid = path.with_extension("key.invalid");
+                println!("[identity]: Persisted key {:?} is unusable, moving it to {:?}. Reason: {}", path, invalid, err);
+
+                if let Err(err) = fs::rename(&path, &invalid) {
+                    println!("[err]: Unable to move unusable key aside; {}", err);
+                }
+            }
+        }
+    }
+
+    let keys = KeyState::generate_pair();
+
+    match persist_identity(&config.state_directory, &keys) {
+        Ok(_) => {},
+        Err(err) => println!("[err]: Unable to persist server identity to {:?}, it will change on restart. Reason: {}", path, err)
+    }
+
+    (keys, IdentitySource::Generated(path))
+}
+
+pub fn imported_key(config: &WireGuardConfigFile) -> Option<&str> {
+    config.imported.as_ref().and_then(| imported | imported.interface.get(&Key::PrivateKey))
+}
+
+// Atomically replaces the persisted key, readable only by the node.
+pub fn persist_identity(state_directory: &str, keys: &KeyState) -> io::Result<()> {
+    fs::create_dir_all(state_directory)?;
+
+    let path = identity_path(state_directory);
+    let temporary = path.with_extension("key.tmp");
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?;
+
+    writeln!(file, "{}", keys.private_key.trim())?;
+    file.sync_all()?;
+
+    fs::rename(&temporary, &path)
+}
+
+// When the persisted key was last written, which is when it was generated or rotated.
+pub fn identity_created(state_directory: &str) -> Option<DateTime<Utc>> {
+    match fs::metadata(identity_path(state_directory)).and_then(| metadata | metadata.modified()) {
+        Ok(modified) => Some(DateTime::<Utc>::from(modified)),
+        Err(_) => None
+    }
+}
+
+pub fn identity_path(state_directory: &str) -> PathBuf {
+    Path::new(state_directory).join(IDENTITY_FILE)
+}
+
+fn read_key(path: &Path) -> Result<KeyState, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => zeroize::Zeroizing::new(contents),
+        Err(err) => return Err(err.to_string())
+    };
+
+    KeyState::from_private_key(&contents).map_err(| err | err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::testing::{config_file, temporary_directory};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn loads_the_identity_in_order_of_precedence() {
+        let directory = temporary_directory();
+        let state_directory = directory.join("state");
+
+        let (configured, secret, imported, persisted) = (KeyState::generate_pair(), KeyState::generate_pair(), KeyState::generate_pair(), KeyState::generate_pair());
+
+        let secret_file = directory.join("secret.key");
+        fs::write(&secret_file, format!("{}\n", secret.private_key.as_str())).unwrap();
+
+        let import_config = directory.join("wg0.conf");
+        fs::write(&import_config, format!("[Interface]\nPrivateKey = {}\nListenPort = 51820\n", imported.private_key.as_str())).unwrap();
+
+        persist_identity(state_directory.to_str().unwrap(), &persisted).unwrap();
+
+        let settings = | keys: &[&str] | {
+            let mut settings = vec![format!("state_directory: {}", state_directory.display())];
+
+            for key in keys {
+                settings.push(match *key {
+                    "private_key" => format!("private_key: {}", configured.private_key.as_str()),
+                    "private_key_file" => format!("private_key_file: {}", secret_file.display()),
+                    _ => format!("import_config: {}", import_config.display())
+                });
+            }
+
+            config_file(&settings.join("\n"))
+        };
+
+        let (keys, source) = load_identity(&settings(&["private_key", "private_key_file", "import_config"]));
+        assert_eq!(keys.public_key, configured.public_key);
+        assert!(matches!(source, IdentitySource::Configuration));
+
+        let (keys, source) = load_identity(&settings(&["private_key_file", "import_config"]));
+        assert_eq!(keys.public_key, secret.public_key);
+        assert!(matches!(source, IdentitySource::SecretFile(path) if path == secret_file));
+
+        let (keys, source) = load_identity(&settings(&["import_config"]));
+        assert_eq!(keys.public_key, imported.public_key);
+        assert!(matches!(source, IdentitySource::Imported(path) if path == import_config));
+
+        let (keys, source) = load_identity(&settings(&[]));
+        assert_eq!(keys.public_key, persisted.public_key);
+        assert_eq!(source.to_string(), identity_path(state_directory.to_str().unwrap()).display().to_string());
+    }
+
+    #[test]
+    fn generates_and_persists_a_missing_identity() {
+        let config = config_file("");
+
+        let (keys, source) = load_identity(&config);
+        let path = identity_path(&config.state_directory);
+
+        assert!(matches!(&source, IdentitySource::Generated(generated) if *generated == path));
+        assert!(source.to_string().ends_with("server.key"));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // The next boot uses the same key.
+        let (reloaded, source) = load_identity(&config);
+        assert_eq!(reloaded.public_key, keys.public_key);
+        assert!(matches!(source, IdentitySource::StateDirectory(_)));
+    }
+
+    #[test]
+    fn sets_an_unusable_identity_aside() {
+        let config = config_file("");
+        let path = identity_path(&config.state_directory);
+
+        fs::create_dir_all(&config.state_directory).unwrap();
+        fs::write(&path, "not a key").unwrap();
+
+        let (_, source) = load_identity(&config);
+
+        assert!(matches!(source, IdentitySource::Generated(_)));
+        assert_eq!(fs::read_to_string(path.with_extension("key.invalid")).unwrap(), "not a key");
+    }
+}
@@ -0,0 +1,284 @@
+use crate::types::{AddressLease, Host, Reservation, Slot};
+use chrono::{Duration, Utc};
+use std::collections::HashSet;
+
+use super::WireGuardConfig;
+
+// Leases are held in `leases` keyed by public key. A leased address stays held in its pool after the session
+// closes, until the lease runs out and the reconciler (or a full pool) gives it back.
+impl WireGuardConfig {
+    pub fn leases_enabled(&self) -> bool {
+        self.config.address_lease_seconds > 0
+    }
+
+    // The slot of an opening session, the address it had before while its lease is valid, otherwise the
+    // first free one.
+    pub fn take_slot(&mut self, public_key: &str, author: &str, tier: &str, requested: Option<&str>) -> Reservation {
+        if let Some(host) = self.renew_lease(public_key, author, tier, requested) {
+            println!("[lease]: {} is given its leased slot {:?}", public_key, host);
+            return Reservation::Held(host);
+        }
+
+        let mut slot = self.find_open_slot(tier, requested);
+
+        // A full pool takes back expired leases, then the idle lease closest to running out.
+        if let Slot::Prospective = slot {
+            self.collect_leases();
+            slot = self.find_open_slot(tier, requested);
+        }
+
+        if let Slot::Prospective = slot {
+            if self.evict_lease(tier, requested) {
+                slot = self.find_open_slot(tier, requested);
+            }
+        }
+
+        println!("[reserver]: Found slot: {:?}", slot);
+
+        let reservation = match slot {
+            Slot::Open(open_slot) => self.reserve_slot(open_slot),
+            Slot::Prospective => Reservation::Imissable
+        };
+
+        if let (true, Reservation::Held(host)) = (self.leases_enabled(), &reservation) {
+            self.leases.insert(public_key.to_string(), AddressLease {
+                host: host.clone(),
+                author: author.to_string(),
+                expires: None
+            });
+        }
+
+        reservation
+    }
+
+    // Called once a session closes, its slot is kept for the lease or freed straight away.
+    pub fn release_slot(&mut self, public_key: &str, host: &Host) {
+        let expires = Utc::now() + Duration::seconds(self.config.address_lease_seconds);
+
+        match self.leases.get_mut(public_key) {
+            Some(lease) if lease.holds(host) => {
+                lease.expires = Some(expires);
+                println!("[lease]: Holding slot {:?} for {} until {}", host, public_key, expires.to_rfc3339());
+            },
+            _ => self.free_slot(host)
+        }
+    }
+
+    // For a session which could not be opened on its slot, which is then neither leased nor held.
+    pub fn drop_lease(&mut self, public_key: &str, host: &Host) {
+        if matches!(self.leases.get(public_key), Some(lease) if lease.holds(host)) {
+            self.leases.remove(public_key);
+        }
+
+        self.free_slot(host);
+    }
+
+    // Frees the slots of every expired lease, returning the public keys they were held for.
+    pub fn collect_leases(&mut self) -> Vec<String> {
+        let now = Utc::now();
+
+        let expired: Vec<String> = self.leases.iter()
+            .filter(| (_, lease) | lease.is_expired(now))
+            .map(| (public_key, _) | public_key.clone())
+            .collect();
+
+        for public_key in &expired {
+            if let Some(lease) = self.leases.remove(public_key) {
+                self.free_slot(&lease.host);
+                println!("[lease]: Lease of {} on {:?} expired", public_key, lease.host);
+            }
+        }
+
+        expired
+    }
+
+    // Leases of sessions which are no longer open at their slot, i.e. ones dropped without being closed,
+    // start running out as if they were closed now. Returns the slots every lease holds.
+    pub fn settle_leases(&mut self, connected: &HashSet<(String, u8, u8)>) -> HashSet<(String, u8, u8)> {
+        let expires = Utc::now() + Duration::seconds(self.config.address_lease_seconds);
+
+        self.leases.values_mut()
+            .map(| lease | {
+                let slot = (lease.host.interface.clone(), lease.host.a, lease.host.b);
+
+                if lease.expires.is_none() && !connected.contains(&slot) {
+                    lease.expires = Some(expires);
+                }
+
+                slot
+            })
+            .collect()
+    }
+
+    // A lease is given back to its public key while it is idle and unexpired, on an interface the client may
+    // use, or while its session is still open. An unusable idle lease is freed, as the client is given another slot.
+    fn renew_lease(&mut self, public_key: &str, author: &str, tier: &str, requested: Option<&str>) -> Option<Host> {
+        let lease = self.leases.get(public_key)?.clone();
+
+        // Still held by an open session of the same key, which keeps its slot until it is closed.
+        if lease.expires.is_none() {
+            return Some(lease.host);
+        }
+
+        let usable = !lease.is_expired(Utc::now())
+            && (!self.config.address_lease_by_author || lease.author == author)
+            && self.serves(&lease.host.interface, tier, requested);
+
+        if !usable {
+            self.leases.remove(public_key);
+            self.free_slot(&lease.host);
+            return None;
+        }
+
+        let host = Host { conn_time: Utc::now(), ..lease.host };
+
+        self.leases.insert(public_key.to_string(), AddressLease {
+            host: host.clone(),
+            author: author.to_string(),
+            expires: None
+        });
+
+        Some(host)
+    }
+
+    // Whether a session of `tier`, asking for the `requested` interface, may be given a slot on `interface`.
+    fn serves(&self, interface: &str, tier: &str, requested: Option<&str>) -> bool {
+        matches!(self.interface(interface), Some(interface) if interface.allows(tier) && requested.is_none_or(| name | interface.name == name))
+    }
+
+    fn evict_lease(&mut self, tier: &str, requested: Option<&str>) -> bool {
+        let evicted = self.leases.iter()
+            .filter(| (_, lease) | lease.expires.is_some())
+            .filter(| (_, lease) | self.serves(&lease.host.interface, tier, requested))
+            .min_by_key(| (_, lease) | lease.expires)
+            .map(| (public_key, _) | public_key.clone());
+
+        match evicted.and_then(| public_key | self.leases.remove(&public_key).map(| lease | (public_key, lease))) {
+            Some((public_key, lease)) => {
+                println!("[lease]: Pool is full, evicting the lease of {} on {:?}", public_key, lease.host);
+                self.free_slot(&lease.host);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::testing::{INTERFACE, node, node_with};
+
+    fn held(reservation: Reservation) -> Host {
+        match reservation {
+            Reservation::Held(host) => host,
+            reservation => panic!("no slot: {:?}", reservation)
+        }
+    }
+
+    fn slot(host: &Host) -> (String, u8, u8) {
+        (host.interface.clone(), host.a, host.b)
+    }
+
+    #[tokio::test]
+    async fn renews_the_lease_of_a_reconnecting_key() {
+        let (mut config, _) = node();
+
+        let host = held(config.take_slot("a", "author", "PRO", None));
+        config.release_slot("a", &host);
+        assert!(config.leases["a"].expires.is_some());
+
+        // The leased slot is not given to anyone else meanwhile.
+        let other = held(config.take_slot("b", "author", "PRO", None));
+        assert_ne!(slot(&other), slot(&host));
+
+        let renewed = held(config.take_slot("a", "author", "PRO", None));
+        assert_eq!(slot(&renewed), slot(&host));
+        assert!(config.leases["a"].expires.is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_slot_of_an_open_session() {
+        let (mut config, _) = node();
+
+        let host = held(config.take_slot("a", "author", "PRO", None));
+        let again = held(config.take_slot("a", "author", "PRO", None));
+
+        assert_eq!(slot(&again), slot(&host));
+        assert!(config.leases["a"].holds(&host));
+
+        // No second slot was held for it, the next key is given the one after.
+        let other = held(config.take_slot("b", "author", "PRO", None));
+        assert_eq!((other.a, other.b), (host.a, host.b + 1));
+    }
+
+    #[tokio::test]
+    async fn frees_leases_taken_by_another_author() {
+        let (mut config, _) = node_with("address_lease_by_author: true");
+
+        let first = held(config.take_slot("b", "author", "PRO", None));
+        let host = held(config.take_slot("a", "author", "PRO", None));
+        config.release_slot("a", &host);
+        config.drop_lease("b", &first);
+
+        let taken = held(config.take_slot("a", "another", "PRO", None));
+
+        // Given the first free slot rather than its lease, which is freed.
+        assert_eq!(slot(&taken), slot(&first));
+        assert_eq!(config.leases["a"].author, "another");
+        assert_eq!(slot(&held(config.take_slot("c", "author", "PRO", None))), slot(&host));
+    }
+
+    #[tokio::test]
+    async fn collects_expired_leases() {
+        let (mut config, _) = node();
+
+        let host = held(config.take_slot("a", "author", "PRO", None));
+        config.release_slot("a", &host);
+
+        assert!(config.collect_leases().is_empty());
+
+        config.leases.get_mut("a").unwrap().expires = Some(Utc::now() - Duration::seconds(1));
+
+        assert_eq!(config.collect_leases(), vec!["a".to_string()]);
+        assert!(config.leases.is_empty());
+        assert_eq!(slot(&held(config.take_slot("b", "author", "PRO", None))), slot(&host));
+    }
+
+    #[tokio::test]
+    async fn evicts_an_idle_lease_from_a_full_pool() {
+        // The server takes 10.9.0.1, leaving a single slot.
+        let (mut config, _) = node_with("
+interfaces:
+  - name: reseda-test
+    listen_port: 8443
+    pool: 10.9.0.0/30
+");
+
+        let host = held(config.take_slot("a", "author", "PRO", None));
+
+        // An open session is never evicted.
+        assert!(matches!(config.take_slot("b", "author", "PRO", None), Reservation::Imissable));
+
+        config.release_slot("a", &host);
+
+        let taken = held(config.take_slot("b", "author", "PRO", None));
+        assert_eq!(slot(&taken), slot(&host));
+        assert!(!config.leases.contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn settles_leases_of_dropped_sessions() {
+        let (mut config, _) = node();
+
+        let connected = held(config.take_slot("a", "author", "PRO", None));
+        let dropped = held(config.take_slot("b", "author", "PRO", None));
+
+        let held_slots = config.settle_leases(&HashSet::from([slot(&connected)]));
+
+        assert_eq!(held_slots, HashSet::from([slot(&connected), slot(&dropped)]));
+        assert!(config.leases["a"].expires.is_none());
+        assert!(config.leases["b"].expires.is_some());
+        assert_eq!(connected.interface, INTERFACE);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::types::PeerStats;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use super::{InterfaceSettings, PeerConfig, WireGuardBackend, WireGuardError};
+
+#[derive(Debug, Clone)]
+pub struct MemoryPeer {
+    pub config: PeerConfig,
+    pub transfer: (i128, i128),
+    pub latest_handshake: Option<DateTime<Utc>>,
+    pub endpoint: Option<SocketAddr>
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryInterface {
+    pub settings: InterfaceSettings,
+    pub peers: HashMap<String, MemoryPeer>
+}
+
+// An in-memory stand-in for the kernel device. Nothing leaves the process, so it needs neither root
+// nor wireguard-tools, and the counters can be driven by hand to exercise usage and idle handling.
+pub struct MemoryBackend {
+    interfaces: Mutex<HashMap<String, MemoryInterface>>
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend {
+            interfaces: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn interface(&self, interface: &str) -> Option<MemoryInterface> {
+        self.interfaces.lock().unwrap().get(interface).cloned()
+    }
+
+    pub fn set_transfer(&self, interface: &str, public_key: &str, up: i128, down: i128) -> Result<(), WireGuardError> {
+        self.with_peer(interface, public_key, | peer | peer.transfer = (up, down))
+    }
+
+    pub fn set_latest_handshake(&self, interface: &str, public_key: &str, handshake: Option<DateTime<Utc>>) -> Result<(), WireGuardError> {
+        self.with_peer(interface, public_key, | peer | peer.latest_handshake = handshake)
+    }
+
+    pub fn set_endpoint(&self, interface: &str, public_key: &str, endpoint: Option<SocketAddr>) -> Result<(), WireGuardError> {
+        self.with_peer(interface, public_key, | peer | peer.endpoint = endpoint)
+    }
+
+    fn with_interface<T>(&self, interface: &str, action: impl FnOnce(&mut MemoryInterface) -> Result<T, WireGuardError>) -> Result<T, WireGuardError> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+
+        match interfaces.get_mut(interface) {
+            Some(device) => action(device),
+            None => Err(WireGuardError::UnknownInterface(interface.to_string()))
+        }
+    }
+
+    fn with_peer(&self, interface: &str, public_key: &str, action: impl FnOnce(&mut MemoryPeer)) -> Result<(), WireGuardError> {
+        self.with_interface(interface, | device | {
+            match device.peers.get_mut(public_key) {
+                Some(peer) => {
+                    action(peer);
+                    Ok(())
+                },
+                None => Err(WireGuardError::UnknownPeer(public_key.to_string()))
+            }
+        })
+    }
+}
+
+impl WireGuardBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn interface_up(&self, interface: &InterfaceSettings) -> Result<(), WireGuardError> {
+        self.interfaces.lock().unwrap().insert(interface.name.clone(), MemoryInterface {
+            settings: interface.clone(),
+            peers: HashMap::new()
+        });
+
+        Ok(())
+    }
+
+    fn interface_down(&self, interface: &str) -> Result<(), WireGuardError> {
+        // Like `wg-quick down`, taking down an interface which does not exist is an error.
+        match self.interfaces.lock().unwrap().remove(interface) {
+            Some(_) => Ok(()),
+            None => Err(WireGuardError::UnknownInterface(interface.to_string()))
+        }
+    }
+
+    fn set_private_key(&self, interface: &str, private_key: &str) -> Result<(), WireGuardError> {
+        self.with_interface(interface, | device | {
+            device.settings.private_key = private_key.trim().to_string();
+            Ok(())
+        })
+    }
+
+    fn add_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        self.with_interface(interface, | device | {
+            let entry = device.peers.entry(peer.public_key.clone()).or_insert(MemoryPeer {
+                config: peer.clone(),
+                transfer: (0, 0),
+                latest_handshake: None,
+                endpoint: None
+            });
+
+            entry.config = peer.clone();
+            Ok(())
+        })
+    }
+
+    fn update_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        self.with_peer(interface, &peer.public_key, | existing | existing.config = peer.clone())
+    }
+
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WireGuardError> {
+        self.with_interface(interface, | device | {
+            // `wg set .. remove` is a no-op for unknown peers, so is this.
+            device.peers.remove(public_key);
+            Ok(())
+        })
+    }
+
+    fn dump(&self, interface: &str) -> Result<Vec<PeerStats>, WireGuardError> {
+        self.with_interface(interface, | device | {
+            Ok(device.peers.values().map(| peer | PeerStats {
+                public_key: peer.config.public_key.clone(),
+                endpoint: peer.endpoint,
+                allowed_ips: peer.config.allowed_ips.clone(),
+                latest_handshake: peer.latest_handshake,
+                rx: peer.transfer.0 as u64,
+                tx: peer.transfer.1 as u64,
+                persistent_keepalive: peer.config.persistent_keepalive
+            }).collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::testing::{INTERFACE, connect, node, node_with};
+
+    fn peer(public_key: &str, allowed_ip: &str) -> PeerConfig {
+        PeerConfig {
+            public_key: public_key.to_string(),
+            allowed_ips: vec![allowed_ip.to_string()],
+            persistent_keepalive: None,
+            preshared_key: None
+        }
+    }
+
+    #[tokio::test]
+    async fn brings_interfaces_up_and_down() {
+        let (config, backend) = node();
+
+        config.config_up().await.unwrap();
+
+        let interface = backend.interface(INTERFACE).unwrap();
+        assert_eq!(interface.settings.private_key, config.keys.private_key.trim());
+        assert_eq!(interface.settings.listen_port, 8443);
+        assert_eq!(interface.settings.addresses[0], "10.8.2.1/16");
+
+        config.config_down().await.unwrap();
+        assert!(backend.interface(INTERFACE).is_none());
+
+        // Like wg-quick, an interface which is not up cannot be taken down.
+        assert!(matches!(config.config_down().await, Err(WireGuardError::UnknownInterface(_))));
+    }
+
+    #[tokio::test]
+    async fn adds_and_removes_peers() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let client = connect(&mut config, "client");
+        config.add_peer(&client).await.unwrap();
+
+        let peers = backend.interface(INTERFACE).unwrap().peers;
+        let added = &peers.get("client").unwrap().config;
+
+        assert_eq!(added.allowed_ips.len(), 2);
+        assert_eq!(added.allowed_ips[0], "10.8.0.1/32");
+        assert!(added.allowed_ips[1].ends_with("/128"));
+        assert_eq!(added.persistent_keepalive, Some(25));
+
+        config.remove_peer(&client).await.unwrap();
+        assert!(backend.interface(INTERFACE).unwrap().peers.is_empty());
+
+        // Removing it again is a no-op, as with `wg set .. remove`.
+        config.remove_peer(&client).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_driven_counters() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let client = connect(&mut config, "client");
+        config.add_peer(&client).await.unwrap();
+
+        let handshake = Utc::now();
+        backend.set_transfer(INTERFACE, "client", 1024, 2048).unwrap();
+        backend.set_latest_handshake(INTERFACE, "client", Some(handshake)).unwrap();
+        backend.set_endpoint(INTERFACE, "client", Some("198.51.100.7:51820".parse().unwrap())).unwrap();
+
+        let stats = config.backend.dump(INTERFACE).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].rx, stats[0].tx), (1024, 2048));
+        assert_eq!(stats[0].latest_handshake, Some(handshake));
+        assert_eq!(stats[0].endpoint, Some("198.51.100.7:51820".parse().unwrap()));
+
+        assert!(matches!(backend.set_transfer(INTERFACE, "unknown", 0, 0), Err(WireGuardError::UnknownPeer(_))));
+        assert!(matches!(backend.set_transfer("missing", "client", 0, 0), Err(WireGuardError::UnknownInterface(_))));
+    }
+
+    #[tokio::test]
+    async fn reconciles_the_interface_against_clients() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        // Connected, but lost from the interface.
+        let missing = connect(&mut config, "missing");
+        // On the interface, without a client.
+        backend.add_peer(INTERFACE, &peer("orphan", "10.8.0.200/32")).unwrap();
+        // On the interface, at the wrong address.
+        let moved = connect(&mut config, "moved");
+        backend.add_peer(INTERFACE, &peer("moved", "10.8.0.201/32")).unwrap();
+
+        {
+            let mut clients = config.clients.lock().await;
+            clients.insert(missing.public_key.clone(), missing);
+            clients.insert(moved.public_key.clone(), moved);
+        }
+
+        let report = config.reconcile().await;
+
+        assert_eq!(report.restored_peers, vec!["missing"]);
+        assert_eq!(report.orphaned_peers, vec!["orphan"]);
+        assert_eq!(report.updated_peers, vec!["moved"]);
+        assert!(report.errors.is_empty());
+
+        let peers = backend.interface(INTERFACE).unwrap().peers;
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers.get("moved").unwrap().config.allowed_ips[0], "10.8.0.2/32");
+
+        assert!(config.reconcile().await.is_clean());
+    }
+
+    #[tokio::test]
+    async fn removes_peers_from_every_interface_despite_failures() {
+        let (mut config, backend) = node_with("
+interfaces:
+  - name: reseda-test0
+    listen_port: 8443
+  - name: reseda-test1
+    listen_port: 8444
+");
+
+        // Only the second interface is up, removing from the first fails.
+        let client = connect(&mut config, "client");
+        backend.interface_up(&config.interface_settings(&config.config.interfaces[1])).unwrap();
+        backend.add_peer("reseda-test1", &peer("client", "10.9.0.1/32")).unwrap();
+
+        assert!(matches!(config.remove_peer(&client).await, Err(WireGuardError::UnknownInterface(_))));
+        assert!(backend.interface("reseda-test1").unwrap().peers.is_empty());
+    }
+}
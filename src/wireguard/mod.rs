@@ -1,3 +1,31 @@
 mod wireguard;
+mod backend;
+mod cli;
+mod memory;
+mod identity;
+mod rotation;
+mod reconcile;
+mod dump;
+mod chain;
+mod forward;
+mod exit_address;
+mod client_config;
+mod lease;
+#[cfg(feature = "netlink")]
+mod netlink;
+#[cfg(test)]
+pub mod testing;
 
-pub use wireguard::*;
\ No newline at end of file
+pub use wireguard::*;
+pub use backend::*;
+pub use cli::*;
+pub use memory::*;
+pub use identity::*;
+pub use rotation::*;
+pub use reconcile::*;
+pub use dump::*;
+pub use chain::*;
+pub use exit_address::*;
+pub use client_config::*;
+#[cfg(feature = "netlink")]
+pub use netlink::*;
\ No newline at end of file
@@ -0,0 +1,165 @@
+use crate::types::PeerStats;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::process::Command;
+use std::sync::Mutex;
+use wireguard_control::{Backend as DeviceBackend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+
+use super::{InterfaceSettings, PeerConfig, WireGuardBackend, WireGuardError};
+
+// Talks to the kernel module over generic netlink, without wireguard-tools.
+// Peer management and statistics are fully native. Interface addressing and the PostUp/PostDown
+// hooks are still applied through `ip` and `sh`, as netlink for WireGuard has no notion of them.
+pub struct NetlinkBackend {
+    // PostDown of every interface brought up, run when it is taken down as wg-quick would.
+    post_down: Mutex<HashMap<String, String>>
+}
+
+impl NetlinkBackend {
+    pub fn new() -> Self {
+        NetlinkBackend {
+            post_down: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn interface_name(interface: &str) -> Result<InterfaceName, WireGuardError> {
+        interface.parse::<InterfaceName>()
+            .map_err(| err | WireGuardError::UnknownInterface(format!("{} ({:?})", interface, err)))
+    }
+
+    fn key(key: &str) -> Result<Key, WireGuardError> {
+        // The key itself is not echoed back, it may be a secret.
+        Key::from_base64(key.trim())
+            .map_err(| _ | WireGuardError::InvalidKey(format!("{} characters", key.len())))
+    }
+
+    fn device(interface: &str) -> Result<Device, WireGuardError> {
+        Device::get(&NetlinkBackend::interface_name(interface)?, DeviceBackend::Kernel)
+            .map_err(| err | WireGuardError::Netlink(err.to_string()))
+    }
+
+    fn peer_builder(peer: &PeerConfig) -> Result<PeerConfigBuilder, WireGuardError> {
+        let mut builder = PeerConfigBuilder::new(&NetlinkBackend::key(&peer.public_key)?)
+            .replace_allowed_ips();
+
+        for allowed_ip in &peer.allowed_ips {
+            let (address, cidr) = match allowed_ip.split_once('/') {
+                Some((address, cidr)) => (address, cidr.parse::<u8>().ok()),
+                None => (allowed_ip.as_str(), None)
+            };
+
+            let address = address.parse::<IpAddr>()
+                .map_err(| err | WireGuardError::Parse(format!("invalid allowed ip '{}', {}", allowed_ip, err)))?;
+
+            let cidr = cidr.unwrap_or(if address.is_ipv4() { 32 } else { 128 });
+            builder = builder.add_allowed_ip(address, cidr);
+        }
+
+        if let Some(keepalive) = peer.persistent_keepalive {
+            builder = builder.set_persistent_keepalive_interval(keepalive);
+        }
+
+        if let Some(preshared_key) = &peer.preshared_key {
+            builder = builder.set_preshared_key(NetlinkBackend::key(preshared_key.as_str())?);
+        }
+
+        Ok(builder)
+    }
+
+    fn apply(interface: &str, update: DeviceUpdate) -> Result<(), WireGuardError> {
+        update.apply(&NetlinkBackend::interface_name(interface)?, DeviceBackend::Kernel)
+            .map_err(| err | WireGuardError::Netlink(err.to_string()))
+    }
+
+    fn shell(command: &str) -> Result<(), WireGuardError> {
+        if command.trim().is_empty() {
+            return Ok(());
+        }
+
+        let output = Command::new("sh")
+            .args(["-c", command])
+            .output()
+            .map_err(| source | WireGuardError::Spawn { command: command.to_string(), source })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(WireGuardError::Command {
+                command: command.to_string(),
+                status: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string()
+            })
+        }
+    }
+}
+
+impl WireGuardBackend for NetlinkBackend {
+    fn name(&self) -> &'static str {
+        "netlink"
+    }
+
+    fn interface_up(&self, interface: &InterfaceSettings) -> Result<(), WireGuardError> {
+        // Applying to a missing kernel interface creates it.
+        NetlinkBackend::apply(&interface.name, DeviceUpdate::new()
+            .set_private_key(NetlinkBackend::key(&interface.private_key)?)
+            .set_listen_port(interface.listen_port)
+            .replace_peers())?;
+
+        for address in &interface.addresses {
+            NetlinkBackend::shell(&format!("ip address replace {} dev {}", address, interface.name))?;
+        }
+
+        NetlinkBackend::shell(&format!("ip link set up dev {}", interface.name))?;
+        self.post_down.lock().unwrap().insert(interface.name.clone(), interface.post_down.clone());
+
+        NetlinkBackend::shell(&interface.post_up)
+    }
+
+    fn interface_down(&self, interface: &str) -> Result<(), WireGuardError> {
+        NetlinkBackend::device(interface)?
+            .delete()
+            .map_err(| err | WireGuardError::Netlink(err.to_string()))?;
+
+        match self.post_down.lock().unwrap().remove(interface) {
+            Some(post_down) => NetlinkBackend::shell(&post_down),
+            None => Ok(())
+        }
+    }
+
+    fn set_private_key(&self, interface: &str, private_key: &str) -> Result<(), WireGuardError> {
+        NetlinkBackend::apply(interface, DeviceUpdate::new().set_private_key(NetlinkBackend::key(private_key)?))
+    }
+
+    fn add_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        NetlinkBackend::apply(interface, DeviceUpdate::new().add_peer(NetlinkBackend::peer_builder(peer)?))
+    }
+
+    fn update_peer(&self, interface: &str, peer: &PeerConfig) -> Result<(), WireGuardError> {
+        let key = NetlinkBackend::key(&peer.public_key)?;
+
+        if !NetlinkBackend::device(interface)?.peers.iter().any(| existing | existing.config.public_key == key) {
+            return Err(WireGuardError::UnknownPeer(peer.public_key.clone()));
+        }
+
+        NetlinkBackend::apply(interface, DeviceUpdate::new().add_peer(NetlinkBackend::peer_builder(peer)?))
+    }
+
+    fn remove_peer(&self, interface: &str, public_key: &str) -> Result<(), WireGuardError> {
+        NetlinkBackend::apply(interface, DeviceUpdate::new().remove_peer_by_key(&NetlinkBackend::key(public_key)?))
+    }
+
+    fn dump(&self, interface: &str) -> Result<Vec<PeerStats>, WireGuardError> {
+        Ok(NetlinkBackend::device(interface)?.peers.iter().map(| peer | PeerStats {
+            public_key: peer.config.public_key.to_base64(),
+            endpoint: peer.config.endpoint,
+            allowed_ips: peer.config.allowed_ips.iter()
+                .map(| allowed_ip | format!("{}/{}", allowed_ip.address, allowed_ip.cidr))
+                .collect(),
+            latest_handshake: peer.stats.last_handshake_time.map(DateTime::<Utc>::from),
+            rx: peer.stats.rx_bytes,
+            tx: peer.stats.tx_bytes,
+            persistent_keepalive: peer.config.persistent_keepalive_interval
+        }).collect())
+    }
+}
@@ -0,0 +1,205 @@
+use crate::types::{Connection, Host};
+use chrono::Utc;
+use futures_timer::Delay;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use super::{WireGuard, WireGuardConfig};
+
+// Every correction made in one pass, logged as a single JSON line so drift can be tracked over time.
+#[derive(Serialize, Debug, Default)]
+pub struct ReconcileReport {
+    // Peers on the interface without a connected client, removed.
+    pub orphaned_peers: Vec<String>,
+    // Connected clients missing from the interface, re-added.
+    pub restored_peers: Vec<String>,
+    // Peers whose allowed-ips no longer matched their slot, updated.
+    pub updated_peers: Vec<String>,
+    // Slots marked as held without a connected client, freed.
+    pub leaked_slots: Vec<String>,
+    // Slots of connected clients which were marked as free, reserved.
+    pub reclaimed_slots: Vec<String>,
+    // Ports forwarded to a session which is no longer connected at that address, released.
+    pub leaked_ports: Vec<u16>,
+    // Public keys whose address lease ran out, their slots freed.
+    pub expired_leases: Vec<String>,
+    pub errors: Vec<String>
+}
+
+impl ReconcileReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_peers.is_empty()
+            && self.restored_peers.is_empty()
+            && self.updated_peers.is_empty()
+            && self.leaked_slots.is_empty()
+            && self.reclaimed_slots.is_empty()
+            && self.leaked_ports.is_empty()
+            && self.expired_leases.is_empty()
+            && self.errors.is_empty()
+    }
+}
+
+impl WireGuardConfig {
+    // Diffs the interface against `clients` and the slot registry, treating `clients` as the source of truth.
+    pub async fn reconcile(&mut self) -> ReconcileReport {
+        let mut report = ReconcileReport::default();
+
+        // Keyed by (interface, public key), as a key may only be present on its clients interface.
+        let mut kernel: HashMap<(String, String), Vec<String>> = HashMap::new();
+
+        for interface in &self.config.interfaces {
+            match self.backend.peers(&interface.name) {
+                Ok(peers) => {
+                    for peer in peers {
+                        let mut allowed_ips = peer.allowed_ips;
+                        allowed_ips.sort();
+                        kernel.insert((interface.name.clone(), peer.public_key), allowed_ips);
+                    }
+                },
+                Err(err) => {
+                    report.errors.push(format!("reading peers of {}: {}", interface.name, err));
+                    return report;
+                }
+            }
+        }
+
+        let clients_reference = self.clients.clone();
+        let clients = clients_reference.lock().await;
+
+        // The servers own slots are held by their pools, and never reported as leaked.
+        let mut held: HashSet<(String, u8, u8)> = HashSet::new();
+
+        for client in clients.values() {
+            let host = match &client.connected {
+                Connection::Connected(host) => host,
+                Connection::Disconnected => continue
+            };
+
+            held.insert((host.interface.clone(), host.a, host.b));
+
+            let expected = match self.peer_config(client) {
+                Ok(peer) => peer,
+                Err(err) => {
+                    report.errors.push(format!("{}: {}", client.public_key, err));
+                    continue;
+                }
+            };
+
+            let mut expected_ips = expected.allowed_ips.clone();
+            expected_ips.sort();
+
+            match kernel.get(&(host.interface.clone(), client.public_key.clone())) {
+                None => match self.backend.add_peer(&host.interface, &expected) {
+                    Ok(_) => report.restored_peers.push(client.public_key.clone()),
+                    Err(err) => report.errors.push(format!("restoring {}: {}", client.public_key, err))
+                },
+                Some(allowed_ips) if *allowed_ips != expected_ips => match self.backend.update_peer(&host.interface, &expected) {
+                    Ok(_) => report.updated_peers.push(client.public_key.clone()),
+                    Err(err) => report.errors.push(format!("updating {}: {}", client.public_key, err))
+                },
+                Some(_) => {}
+            }
+        }
+
+        for (interface, public_key) in kernel.keys() {
+            let connected = match clients.get(public_key) {
+                Some(client) => match &client.connected {
+                    Connection::Connected(host) => host.interface == *interface,
+                    Connection::Disconnected => false
+                },
+                None => false
+            };
+
+            if !connected {
+                match self.backend.remove_peer(interface, public_key) {
+                    Ok(_) => report.orphaned_peers.push(public_key.clone()),
+                    Err(err) => report.errors.push(format!("removing {}: {}", public_key, err))
+                }
+            }
+        }
+
+        let stale_forwards: Vec<String> = self.forwards.values()
+            .filter(| forward | {
+                let host = clients.get(&forward.owner).and_then(| client | match &client.connected {
+                    Connection::Connected(host) => Some(host),
+                    Connection::Disconnected => None
+                });
+
+                match host {
+                    Some(host) => host.interface != forward.interface
+                        || self.interface(&host.interface).map(| interface | interface.host_ipv4(host)) != Some(forward.address),
+                    None => true
+                }
+            })
+            .map(| forward | forward.owner.clone())
+            .collect();
+
+        drop(clients);
+
+        for owner in stale_forwards {
+            report.leaked_ports.extend(self.release_ports(&owner));
+        }
+
+        report.expired_leases = self.collect_leases();
+
+        // Slots leased to a public key stay held after its session closes.
+        let leased = self.settle_leases(&held);
+        held.extend(leased);
+
+        let leaked: Vec<Host> = self.config.interfaces.iter()
+            .filter_map(| interface | self.registry.get(&interface.name).map(| registry | (interface, registry)))
+            .flat_map(| (interface, registry) | registry.held().map(move | address | interface.host(address)))
+            .filter(| host | !held.contains(&(host.interface.clone(), host.a, host.b)))
+            .collect();
+
+        for host in leaked {
+            self.free_slot(&host);
+            report.leaked_slots.push(self.slot_name(&host));
+        }
+
+        for (interface, a, b) in held {
+            let host = Host { interface, a, b, conn_time: Utc::now() };
+
+            let reserved = match (self.interface(&host.interface), self.registry.get(&host.interface)) {
+                (Some(interface), Some(registry)) => Some(registry.is_held(interface.host_ipv4(&host))),
+                _ => None
+            };
+
+            if reserved == Some(false) {
+                report.reclaimed_slots.push(self.slot_name(&host));
+                self.reserve_slot(host);
+            }
+        }
+
+        report
+    }
+
+    fn slot_name(&self, host: &Host) -> String {
+        match self.interface(&host.interface) {
+            Some(interface) => format!("{}/{}", interface.name, interface.host_ipv4(host)),
+            None => format!("{}/{}.{}", host.interface, host.a, host.b)
+        }
+    }
+}
+
+pub async fn reconciler(config: WireGuard) {
+    let interval = config.lock().await.config.reconcile_interval_seconds;
+
+    if interval <= 0 {
+        println!("[reconcile]: Peer reconciliation disabled");
+        return;
+    }
+
+    loop {
+        Delay::new(std::time::Duration::from_secs(interval as u64)).await;
+
+        let report = config.lock().await.reconcile().await;
+
+        if !report.is_clean() {
+            match serde_json::to_string(&report) {
+                Ok(report) => println!("[reconcile]: {}", report),
+                Err(err) => println!("[err]: Unable to serialize reconcile report, {}", err)
+            }
+        }
+    }
+}
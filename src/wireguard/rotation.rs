@@ -0,0 +1,300 @@
+use crate::types::{Connection, KeyState, RekeyNotice, ServerMessage};
+use chrono::{DateTime, Duration, Utc};
+use futures_timer::Delay;
+use std::collections::HashSet;
+
+use super::{WireGuard, WireGuardConfig, WireGuardError, imported_key, persist_identity};
+
+#[derive(Clone)]
+pub struct PendingRotation {
+    pub keys: KeyState,
+    pub deadline: DateTime<Utc>,
+    // Public keys of clients which have acknowledged the new server key.
+    pub acknowledged: HashSet<String>
+}
+
+// A rotation happens in two phases. When it begins, the new key is announced to every connected client, and
+// to every client connecting until it completes, while the interface keeps serving the old one. An interface
+// holds a single key on its port, so the old key stops working once it is swapped: only once every connected
+// client has acknowledged the announcement (or the grace period runs out). Peers are kept, and clients which
+// already know the new key simply re-handshake.
+impl WireGuardConfig {
+    pub fn rotation_enabled(&self) -> bool {
+        // A key given by the operator (config, secret file or imported file) is theirs to rotate, not ours.
+        self.config.key_rotation_hours > 0
+            && self.config.private_key.is_none()
+            && self.config.private_key_file.is_none()
+            && imported_key(&self.config).is_none()
+    }
+
+    pub fn rotation_due(&self) -> bool {
+        self.rotation.is_none()
+            && Utc::now() - self.rotated_at >= Duration::hours(self.config.key_rotation_hours)
+    }
+
+    pub async fn begin_rotation(&mut self) {
+        let keys = KeyState::generate_pair();
+        let deadline = Utc::now() + Duration::seconds(self.config.key_rotation_grace_seconds);
+
+        let public_key = keys.public_key.clone();
+
+        self.rotation = Some(PendingRotation {
+            keys,
+            deadline,
+            acknowledged: HashSet::new()
+        });
+
+        let notice = match self.rekey_notice() {
+            Some(notice) => notice,
+            None => return
+        };
+
+        let clients = self.clients.lock().await;
+        let mut notified = 0;
+
+        for client in clients.values() {
+            if let Connection::Connected(_) = client.connected {
+                if client.send_message(&notice) {
+                    notified += 1;
+                }
+            }
+        }
+
+        drop(clients);
+
+        println!("[rotation]: Announced server key {} to {} clients, switching by {}", public_key, notified, deadline);
+    }
+
+    // The announcement of the pending rotation, if any. Sessions opened after it began are sent it as well.
+    pub fn rekey_notice(&self) -> Option<ServerMessage> {
+        self.rotation.as_ref().map(| pending | ServerMessage::Rekey(RekeyNotice {
+            server_public_key: pending.keys.public_key.clone(),
+            deadline: pending.deadline.to_rfc3339()
+        }))
+    }
+
+    pub fn acknowledge_rotation(&mut self, client_id: &str) -> bool {
+        match &mut self.rotation {
+            Some(pending) => {
+                pending.acknowledged.insert(client_id.to_string());
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn rotation_ready(&self) -> bool {
+        match &self.rotation {
+            Some(pending) => {
+                if Utc::now() >= pending.deadline {
+                    return true;
+                }
+
+                let clients = self.clients.lock().await;
+
+                clients.values()
+                    .filter(| client | matches!(client.connected, Connection::Connected(_)))
+                    .all(| client | pending.acknowledged.contains(&client.public_key))
+            },
+            None => false
+        }
+    }
+
+    pub async fn complete_rotation(&mut self) -> Result<(), WireGuardError> {
+        let pending = match self.rotation.take() {
+            Some(pending) => pending,
+            None => return Ok(())
+        };
+
+        // Every interface shares the one server identity.
+        for interface in &self.config.interfaces {
+            if let Err(err) = self.backend.set_private_key(&interface.name, &pending.keys.private_key) {
+                // Leave the rotation pending so it is retried, clients already know the key.
+                self.rotation = Some(pending);
+                return Err(err);
+            }
+        }
+
+        self.keys = pending.keys;
+        self.rotated_at = Utc::now();
+
+        // Keep the on-disk interface configuration and identity in step with the interfaces.
+        for interface in &self.config.interfaces {
+            let config = self.generate_config_string(interface).await;
+            self.write_config(interface, &config);
+        }
+
+        if let Err(err) = persist_identity(&self.config.state_directory, &self.keys) {
+            println!("[err]: Unable to persist rotated server identity, it will revert on restart. Reason: {}", err);
+        }
+
+        let notice = ServerMessage::RekeyComplete(RekeyNotice {
+            server_public_key: self.keys.public_key.clone(),
+            deadline: pending.deadline.to_rfc3339()
+        });
+
+        for client in self.clients.lock().await.values() {
+            if let Connection::Connected(_) = client.connected {
+                client.send_message(&notice);
+            }
+        }
+
+        println!("[rotation]: Server key rotated to {}, {} clients had acknowledged", self.keys.public_key, pending.acknowledged.len());
+
+        Ok(())
+    }
+}
+
+pub async fn rotation_scheduler(config: WireGuard) {
+    if !config.lock().await.rotation_enabled() {
+        println!("[rotation]: Server key rotation disabled");
+        return;
+    }
+
+    loop {
+        // Task will run every *1m*
+        Delay::new(std::time::Duration::from_secs(60)).await;
+
+        let mut configuration = config.lock().await;
+
+        if configuration.rotation_due() {
+            configuration.begin_rotation().await;
+        } else if configuration.rotation_ready().await {
+            if let Err(err) = configuration.complete_rotation().await {
+                println!("[err]: Unable to apply rotated server key, retrying. Reason: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::identity_path;
+    use std::fs;
+    use crate::wireguard::testing::{INTERFACE, client, connect, node};
+    use tokio::sync::mpsc;
+    use warp::ws::Message;
+
+    type Receiver = mpsc::UnboundedReceiver<Result<Message, warp::Error>>;
+
+    // A connected client, the messages it is sent are read back from the returned receiver.
+    async fn listener(config: &mut WireGuardConfig, public_key: &str) -> Receiver {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let mut client = connect(config, public_key);
+        client.sender = Some(sender);
+
+        config.clients.lock().await.insert(public_key.to_string(), client);
+        receiver
+    }
+
+    fn messages(receiver: &mut Receiver) -> Vec<serde_json::Value> {
+        let mut messages = vec![];
+
+        while let Ok(Ok(message)) = receiver.try_recv() {
+            messages.push(serde_json::from_str(message.to_str().unwrap()).unwrap());
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn swaps_the_key_once_every_client_acknowledged() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let mut first = listener(&mut config, "first").await;
+        let mut second = listener(&mut config, "second").await;
+
+        // Disconnected clients are neither told nor waited on.
+        config.clients.lock().await.insert("idle".to_string(), client("idle", "author"));
+
+        let previous = config.keys.public_key.clone();
+        config.begin_rotation().await;
+
+        let announced = config.rotation.as_ref().unwrap().keys.public_key.clone();
+
+        for receiver in [&mut first, &mut second] {
+            let sent = messages(receiver);
+
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0]["type"], "rekey");
+            assert_eq!(sent[0]["message"]["server_public_key"], announced.as_str());
+        }
+
+        // The interface keeps serving the old key meanwhile.
+        assert!(!config.rotation_ready().await);
+        assert_eq!(config.keys.public_key, previous);
+
+        assert!(config.acknowledge_rotation("first"));
+        assert!(!config.rotation_ready().await);
+
+        assert!(config.acknowledge_rotation("second"));
+        assert!(config.rotation_ready().await);
+
+        config.complete_rotation().await.unwrap();
+
+        assert!(config.rotation.is_none());
+        assert_eq!(config.keys.public_key, announced);
+        assert_eq!(backend.interface(INTERFACE).unwrap().settings.private_key, config.keys.private_key.trim());
+        assert_eq!(fs::read_to_string(identity_path(&config.config.state_directory)).unwrap().trim(), config.keys.private_key.trim());
+
+        for receiver in [&mut first, &mut second] {
+            let sent = messages(receiver);
+
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0]["type"], "rekey_complete");
+            assert_eq!(sent[0]["message"]["server_public_key"], announced.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn swaps_the_key_once_the_grace_period_ran_out() {
+        let (mut config, backend) = node();
+        config.config_up().await.unwrap();
+
+        let _first = listener(&mut config, "first").await;
+
+        config.begin_rotation().await;
+        assert!(!config.rotation_ready().await);
+
+        config.rotation.as_mut().unwrap().deadline = Utc::now() - Duration::seconds(1);
+        assert!(config.rotation_ready().await);
+
+        let announced = config.rotation.as_ref().unwrap().keys.public_key.clone();
+        config.complete_rotation().await.unwrap();
+
+        assert_eq!(config.keys.public_key, announced);
+        assert_eq!(backend.interface(INTERFACE).unwrap().settings.private_key, config.keys.private_key.trim());
+    }
+
+    #[tokio::test]
+    async fn announces_the_rotation_to_sessions_opened_during_it() {
+        let (mut config, _) = node();
+        config.config_up().await.unwrap();
+
+        assert!(config.rekey_notice().is_none());
+        assert!(!config.acknowledge_rotation("late"));
+
+        config.begin_rotation().await;
+
+        // Sent by `open_query` along with the open reply.
+        match config.rekey_notice() {
+            Some(ServerMessage::Rekey(notice)) => {
+                let pending = config.rotation.as_ref().unwrap();
+
+                assert_eq!(notice.server_public_key, pending.keys.public_key);
+                assert_eq!(notice.deadline, pending.deadline.to_rfc3339());
+            },
+            notice => panic!("unexpected {:?}", notice)
+        }
+
+        // A session which opened since has to acknowledge as well.
+        let _late = listener(&mut config, "late").await;
+        assert!(!config.rotation_ready().await);
+
+        assert!(config.acknowledge_rotation("late"));
+        assert!(config.rotation_ready().await);
+    }
+}
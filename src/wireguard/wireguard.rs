@@ -1,4 +1,6 @@
-use crate::types::{WireGuardConfigFile, Clients, KeyState, Client, Host, Reservation, Slot, Connection};
+use crate::types::{AddressLease, AddressPool, WireGuardConfigFile, Clients, KeyState, Client, Host, InterfaceDefinition, Key, OveragePolicy, PortForward, Reservation, Slot, Connection, WgQuickConfig};
+use crate::firewall::{Firewall, ShapedPeer};
+use super::{Backend, ExitAssignments, ChainedSession, InterfaceSettings, PeerConfig, WireGuardError, PendingRotation, backend_from_name, identity_created, load_identity};
 use std::collections::BTreeMap;
 use std::os::raw::c_float;
 use std::{collections::HashMap, sync::Arc};
@@ -6,8 +8,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::mysql::MySqlPoolOptions;
 use sqlx::{Pool, MySql};
 use tokio::sync::{Mutex};
-use std::process::{Command};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use reqwest;
 
 use std::fs::File;
@@ -22,10 +23,27 @@ pub struct WireGuardConfig {
     pub config: WireGuardConfigFile,
     pub keys: KeyState,
     pub clients: Clients,
+    pub backend: Backend,
+    pub firewall: Firewall,
+
+    pub rotation: Option<PendingRotation>,
+    pub rotated_at: DateTime<Utc>,
+
+    // Sessions chained clients hold with their exit node, keyed by client public key.
+    pub chained: HashMap<String, ChainedSession>,
+
+    // Set once a shutdown has begun, no new sessions are accepted.
+    pub draining: bool,
 
     pub pool: Pool<MySql>,
-    pub registry: BTreeMap<u8, BTreeMap<u8, bool>>,
-    pub internal_addr: String,
+    // Addresses held on each interface, keyed by interface name.
+    pub registry: HashMap<String, AddressPool>,
+    // Slots kept for public keys to reconnect to, see `take_slot`.
+    pub leases: HashMap<String, AddressLease>,
+    // Forwarded public ports, see `forward_port`.
+    pub forwards: BTreeMap<u16, PortForward>,
+    // Exit addresses of sessions, see `assign_exit_address`.
+    pub exit_assignments: ExitAssignments,
 
     pub information: RegistryReturn
 }
@@ -76,10 +94,14 @@ impl WireGuardConfig {
     pub async fn initialize() -> Self {
         // Import configuration from environment
         let res = WireGuardConfigFile::from_environment().await;
-        // Generate Keys
-        let keys = KeyState::generate_pair();
-        // Initialize IP Registry (maps 65025 possible IP addresses)
-        let registry = WireGuardConfig::init_registry(12);
+        // Load (or generate and persist) the servers identity
+        let (keys, source) = load_identity(&res);
+        println!("[identity]: Using server public key {} from {}", keys.public_key, source);
+        // Select how the interface is driven (wg-quick, netlink or in-memory)
+        let backend = match backend_from_name(&res.backend) {
+            Ok(backend) => backend,
+            Err(err) => panic!("[err]: Selecting wireguard backend. Reason: {}", err)
+        };
 
         let pool = match MySqlPoolOptions::new()
             .max_connections(5)
@@ -93,17 +115,40 @@ impl WireGuardConfig {
                 }
         };
 
+        // Adds the columns this node writes, i.e. `overage` on Usage, before any session is recorded.
+        if let Err(error) = sqlx::migrate!("./migrations").run(&pool).await {
+            panic!("[service] sqlx::error Failed to run migrations. Reason: {}", error);
+        }
+
         let registry_return = WireGuardConfig::register_server(&res).await;
 
+        WireGuardConfig::new(res, keys, backend, pool, registry_return)
+    }
+
+    // A node without any sessions, its interfaces not yet up.
+    pub fn new(res: WireGuardConfigFile, keys: KeyState, backend: Backend, pool: Pool<MySql>, information: RegistryReturn) -> Self {
+        let firewall = Firewall::from_config(&res.firewall, &res.interfaces, res.rate_limits(), res.port_forward_range.is_some(), res.exit_addresses.is_enabled());
+
+        // Initialize an address pool for every interface, holding its server address
+        let registry = WireGuardConfig::init_registry(&res.interfaces);
+
         // Return Configuration
         WireGuardConfig {
+            rotated_at: identity_created(&res.state_directory).unwrap_or(Utc::now()),
             config: res,
             keys: keys,
             clients: Arc::new(Mutex::new(HashMap::new())),
+            backend: backend,
+            firewall: firewall,
+            rotation: None,
+            chained: HashMap::new(),
+            draining: false,
             pool: pool,
             registry: registry,
-            internal_addr: "10.8.2.1".to_string(),
-            information: registry_return
+            leases: HashMap::new(),
+            forwards: BTreeMap::new(),
+            exit_assignments: ExitAssignments::default(),
+            information: information
         }
     }
 
@@ -186,153 +231,241 @@ impl WireGuardConfig {
         registration_return
     }
 
-    pub fn init_registry(highest: u8) -> BTreeMap<u8, BTreeMap<u8, bool>> {
-        let mut registry: BTreeMap<u8, BTreeMap<u8, bool>> = BTreeMap::new();
-
-        for i in 2..highest {
-            let mut new_map = BTreeMap::new();
+    pub fn init_registry(interfaces: &[InterfaceDefinition]) -> HashMap<String, AddressPool> {
+        interfaces.iter()
+            .map(| interface | (interface.name.clone(), AddressPool::new(interface.pool, interface.address)))
+            .collect()
+    }
 
-            for k in 1..255 {
-                new_map.insert(k, false);
-            }
+    pub fn interface(&self, name: &str) -> Option<&InterfaceDefinition> {
+        self.config.interfaces.iter().find(| interface | interface.name == name)
+    }
 
-            registry.insert(i, new_map);
+    // Resolvers a client on `interface` should use, the built-in resolver unless it is disabled.
+    pub fn client_dns(&self, interface: &InterfaceDefinition) -> Vec<String> {
+        match self.config.resolver.enabled {
+            true => interface.dns_addresses().iter().map(| address | address.to_string()).collect(),
+            false => vec![self.config.dns.clone()]
         }
-
-        registry
     }
 
-    pub async fn save_config(&mut self, should_restart: bool) -> &mut Self {
-        let config = &self.generate_config_string().await;
-
-        match fs::write("/etc/wireguard/reseda.conf", config) {
+    pub fn write_config(&self, interface: &InterfaceDefinition, config: &str) {
+        match fs::write(interface.config_path(), config) {
             Result::Err(err) => {
-                println!("[err]: Unable to write configuration. Reason: {:?}", err);
+                println!("[err]: Unable to write configuration for {}. Reason: {:?}", interface.name, err);
             },
             Result::Ok(_) => {}
         }
+    }
+
+    pub async fn save_config(&mut self, should_restart: bool) -> &mut Self {
+        for interface in self.config.interfaces.clone() {
+            let config = &self.generate_config_string(&interface).await;
+
+            self.write_config(&interface, config);
+        }
         
         if should_restart {
-            WireGuardConfig::restart_config(self).await;
+            match WireGuardConfig::restart_config(self).await {
+                Ok(_) => {},
+                Err(err) => println!("[err]: Unable to bring up interfaces. Reason: {}", err)
+            }
         }
 
-        // Using:: https://gist.github.com/qdm12/4e0e4f9d1a34db9cf63ebb0997827d0d
-        // Try to implement localized security policies such that data cannot be shared domestically
-        // and users on the VPN cannot access eachother.
-
-        match self.reserve_slot(Host { a: 2, b: 1, conn_time: Utc::now() }) {
-            Reservation::Held(reservation) => println!("[reserver]: Default Server Slot held; {:?}", reservation),
-            Reservation::Detached(detached) => println!("[reserver]: Error, Slot debounced as detached. {:?}", detached),
-            Reservation::Imissable => println!("[reserver]: Error, Slot returned IMISSABLE"),
-        }
+        // Users on the VPN cannot access eachother, see `client_isolation`, enforced by the interfaces PostUp rules.
+        // The servers own address is held by its pool from the start, see `AddressPool`.
 
         self
     }
 
-    pub async fn generate_config_string(&self) -> String {
-        let mut elems = vec!["[Interface]".to_string()];
-        elems.push(format!("Address = {}/24", &self.internal_addr));
-        elems.push(format!("PrivateKey = {}", &self.keys.private_key.trim()));
-        elems.push(format!("ListenPort = {}", &self.config.listen_port));
-        elems.push(format!("DNS = {}", &self.config.dns));
-        elems.push(format!("PostUp = {}", &self.config.post_up));
-        elems.push(format!("PostDown = {}", &self.config.post_down));
+    pub async fn generate_config_string(&self, interface: &InterfaceDefinition) -> String {
+        self.wg_quick_config(interface).to_string()
+    }
+
+    // Starts from the imported file, if any, so keys the node does not manage (MTU, Table, FwMark, PreUp, ..)
+    // are kept. Everything the node does manage is replaced, including the PostUp/PostDown hooks, as the
+    // firewall rules they installed are the nodes own now.
+    pub fn wg_quick_config(&self, interface: &InterfaceDefinition) -> WgQuickConfig {
+        let mut config = match &self.config.imported {
+            Some(imported) => imported.without_peers(),
+            None => WgQuickConfig::new()
+        };
 
-        elems.join("\n")
+        config.interface
+            .set(Key::Address, interface.addresses().join(", "))
+            .set(Key::PrivateKey, self.keys.private_key.trim())
+            .set(Key::ListenPort, interface.listen_port.to_string())
+            .set(Key::Dns, self.config.dns.clone())
+            .set(Key::PostUp, self.firewall.post_up(interface))
+            .set(Key::PostDown, self.firewall.post_down(interface));
+
+        config
     }
 
-    pub async fn restart_config(&mut self) -> bool {
-        let down_status = &self.config_down().await;
-        let up_status = &self.config_up().await;
+    pub fn interface_settings(&self, interface: &InterfaceDefinition) -> InterfaceSettings {
+        InterfaceSettings {
+            name: interface.name.clone(),
+            private_key: self.keys.private_key.trim().to_string(),
+            listen_port: interface.listen_port,
+            addresses: interface.addresses(),
+            post_up: self.firewall.post_up(interface),
+            post_down: self.firewall.post_down(interface)
+        }
+    }
+
+    pub async fn restart_config(&mut self) -> Result<(), WireGuardError> {
+        // On a fresh node the interfaces are not up yet, so failing to take them down is expected.
+        if let Err(err) = self.config_down().await {
+            println!("[wg]: Unable to take down interfaces, {}", err);
+        }
 
-        *up_status && *down_status
+        self.config_up().await
     }
     
-    pub async fn remove_peer(&self, client: &Client) {
-        match Command::new("wg")
-            .env("export WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")
-            .args(["set", "reseda", "peer", &client.public_key, "remove"]).output() {
-                Ok(output) => {
-                    println!("[wg]: Remove Peer {:?}", output);
-                }
-                Err(err) => {
-                    println!("[wg]: Failed to bring up reseda server, {:?}", err);
+    pub async fn remove_peer(&self, client: &Client) -> Result<(), WireGuardError> {
+        // Shaping is keyed by slot, so it can only be removed while the client still holds one.
+        if let Some(shape) = self.shaped_peer(client) {
+            if let Err(err) = self.firewall.unshape_peer(&shape) {
+                println!("[err]: Unable to remove rate limit of {}, {}", client.public_key, err);
+            }
+        }
+
+        // The client may already be disconnected, so remove it from wherever it is.
+        // Removing an unknown peer is a no-op. Every interface is tried, the first failure is returned.
+        let mut result = Ok(());
+
+        for interface in &self.config.interfaces {
+            if let Err(err) = self.backend.remove_peer(&interface.name, &client.public_key) {
+                println!("[err]: Unable to remove peer {} from {}, {}", client.public_key, interface.name, err);
+
+                if result.is_ok() {
+                    result = Err(err);
                 }
+            }
         }
+
+        if result.is_ok() {
+            println!("[wg]: Removed Peer {}", client.public_key);
+        }
+
+        result
     }
 
-    pub async fn add_peer(&self, client: &Client) {
+    pub fn peer_config(&self, client: &Client) -> Result<PeerConfig, WireGuardError> {
         match &client.connected {
             Connection::Disconnected => {
-                println!("[err]: Attempted to add peer that was DISCONNECTED.")
+                Err(WireGuardError::PeerDisconnected(client.public_key.clone()))
             },
             Connection::Connected(connection) => {
-                match Command::new("wg")
-                    .env("export WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")
-                    .args(["set", "reseda", "peer", &client.public_key, "allowed-ips", &format!("10.8.{}.{}", connection.a, connection.b), "persistent-keepalive", "25"]).output() {
-                        Ok(output) => {
-                            println!("[wg]: wg-quick: Add Peer: {:?}", output);
-                        }
-                        Err(err) => {
-                            println!("[wg]: wg-quick: Failed to bring up reseda server, {:?}", err);
-                        }
+                let interface = match self.interface(&connection.interface) {
+                    Some(interface) => interface,
+                    None => return Err(WireGuardError::UnknownInterface(connection.interface.clone()))
+                };
+
+                let mut allowed_ips = vec![format!("{}/32", interface.host_ipv4(connection))];
+
+                if let Some(ipv6) = interface.host_ipv6(connection) {
+                    allowed_ips.push(format!("{}/128", ipv6));
                 }
+
+                Ok(PeerConfig {
+                    public_key: client.public_key.clone(),
+                    allowed_ips,
+                    persistent_keepalive: Some(25),
+                    preshared_key: client.preshared_key.clone()
+                })
             },
         }
-        
     }
 
-    pub async fn config_up(&self) -> bool {
-        match Command::new("wg-quick")
-            .env("export WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")    
-            .args(["up", "reseda"]).output() {
-                Ok(_) => {
-                    println!("[wg]: wg-quick: up");
-                    true
-                }
-                Err(err) => {
-                    println!("[wg]: wg-quick: Failed to bring up reseda server, {:?}", err);
-                    false
-                }
+    pub async fn add_peer(&self, client: &Client) -> Result<(), WireGuardError> {
+        let peer = self.peer_config(client)?;
+
+        if let Connection::Connected(connection) = &client.connected {
+            self.backend.add_peer(&connection.interface, &peer)?;
+            println!("[wg]: Added Peer {} on {} {}", client.public_key, connection.interface, peer.allowed_ips.join(", "));
         }
-    }
 
-    pub async fn config_down(&self) -> bool {
-        match Command::new("wg-quick")
-            .env("export WG_I_PREFER_BUGGY_USERSPACE_TO_POLISHED_KMOD", "1")    
-            .args(["down", "reseda"])
-            .output() {
-            Ok(_) => {
-                println!("[wg] wg-quick: down");
-                true
+        if let Some(shape) = self.shaped_peer(client) {
+            // An unshaped peer is still usable, so this does not fail the peer.
+            match self.firewall.shape_peer(&shape) {
+                Ok(_) => println!("[wg]: Limited Peer {} to {}kbit", client.public_key, shape.kbit),
+                Err(err) => println!("[err]: Unable to rate limit {}, {}", client.public_key, err)
             }
-            Err(err) => {
-                println!("[wg]: wg-quick: Failed to take down reseda server, {:?}", err);
-                false
+        }
+
+        Ok(())
+    }
+
+    // Brings the rate limit of a connected client in line with its (changed) tier.
+    pub fn reshape_peer(&self, client: &Client, previous: Option<ShapedPeer>) {
+        let result = match (self.shaped_peer(client), previous) {
+            (Some(shape), _) => self.firewall.shape_peer(&shape),
+            (None, Some(previous)) => self.firewall.unshape_peer(&previous),
+            (None, None) => Ok(())
+        };
+
+        if let Err(err) = result {
+            println!("[err]: Unable to update rate limit of {}, {}", client.public_key, err);
+        }
+    }
+
+    // The rate limit of a connected client, None when it is unlimited. A throttled overage overrides the tier.
+    pub fn shaped_peer(&self, client: &Client) -> Option<ShapedPeer> {
+        let connection = match &client.connected {
+            Connection::Connected(connection) => connection,
+            Connection::Disconnected => return None
+        };
+
+        let interface = self.interface(&connection.interface)?;
+        let kbit = match client.overage {
+            Some(OveragePolicy::Throttle { kbit }) => kbit,
+            _ => client.maximums.rate_limit_kbit()?
+        };
+
+        Some(ShapedPeer {
+            interface: interface.name.clone(),
+            id: (connection.a as u16) << 8 | connection.b as u16,
+            ipv4: interface.host_ipv4(connection),
+            ipv6: interface.host_ipv6(connection),
+            kbit
+        })
+    }
+
+    pub async fn config_up(&self) -> Result<(), WireGuardError> {
+        for interface in &self.config.interfaces {
+            self.backend.interface_up(&self.interface_settings(interface))?;
+            println!("[wg]: {}: {} up on port {}", self.backend.name(), interface.name, interface.listen_port);
+        }
+
+        Ok(())
+    }
+
+    pub async fn config_down(&self) -> Result<(), WireGuardError> {
+        let mut result = Ok(());
+
+        // Attempt every interface, even if an earlier one was not up.
+        for interface in &self.config.interfaces {
+            match self.backend.interface_down(&interface.name) {
+                Ok(_) => println!("[wg]: {}: {} down", self.backend.name(), interface.name),
+                Err(err) => result = Err(err)
             }
         }
+
+        result
     }
 
-    pub fn find_open_slot(&self) -> Slot {
-        for i in 2..self.registry.len() as u8 {
-            for k in 1..255 {
-                match self.registry.get(&i) {
-                    Some(a_val) => {
-                        match a_val.get(&k) {
-                            Some(value) => {
-                                match value {
-                                    false => {
-                                        // Pre-emptive return, we have found an open slot and we can reserve it from here.
-                                        return Slot::Open(Host { a: i, b: k, conn_time: Utc::now() })
-                                    }
-                                    true => {}
-                                }
-                            }
-                            None => {}
-                        }
-                    },
-                    None => {},
-                }
+    // Interfaces are tried in configured order, skipping those which do not allow the clients tier.
+    // A client may ask for a specific interface, i.e. one on a port its network does not block.
+    pub fn find_open_slot(&self, tier: &str, requested: Option<&str>) -> Slot {
+        let candidates = self.config.interfaces.iter()
+            .filter(| interface | interface.allows(tier))
+            .filter(| interface | requested.is_none_or(| name | interface.name == name));
+
+        for interface in candidates {
+            // Pre-emptive return, we have found an open slot and we can reserve it from here.
+            if let Some(address) = self.registry.get(&interface.name).and_then(| registry | registry.next_free()) {
+                return Slot::Open(interface.host(address));
             }
         }
 
@@ -340,41 +473,35 @@ impl WireGuardConfig {
     }
 
     pub fn reserve_slot(&mut self, requested_slot: Host) -> Reservation {
-        match self.registry.get_mut(&requested_slot.a) {
-            Some(slot_a) => {
-                match slot_a.get_key_value(&requested_slot.b) {
-                    Some(slot_b) => {
-                        match slot_b.1 {
-                            &false => {
-                                slot_a.entry(requested_slot.b).and_modify(| val | { *val = true });
-                                Reservation::Held(requested_slot)
-                            }
-                            &true => { 
-                                println!("[err]: Assigning slot {:?} failed. Reason: Slot had value TRUE", requested_slot);
-                                Reservation::Detached(requested_slot)
-                            }
-                        }
-                    }
-                    None => {
-                        println!("[err]: Assigning slot {:?} failed. Reason: Slot did not have a valid/existing b value.", requested_slot);
-                        Reservation::Detached(requested_slot)
-                    }
-                }
-            }
+        let address = match self.interface(&requested_slot.interface) {
+            Some(interface) => interface.host_ipv4(&requested_slot),
             None => {
-                println!("[err]: Assigning slot {:?} failed. Reason: Slot did not have a valid/existing a value.", requested_slot);
+                println!("[err]: Assigning slot {:?} failed. Reason: Slot did not have a valid/existing interface.", requested_slot);
+                return Reservation::Detached(requested_slot);
+            }
+        };
+
+        match self.registry.get_mut(&requested_slot.interface).map(| registry | registry.hold(address)) {
+            Some(Ok(_)) => Reservation::Held(requested_slot),
+            Some(Err(reason)) => {
+                println!("[err]: Assigning slot {:?} failed. Reason: {}", requested_slot, reason);
                 Reservation::Detached(requested_slot)
             },
+            None => {
+                println!("[err]: Assigning slot {:?} failed. Reason: Interface has no address pool.", requested_slot);
+                Reservation::Detached(requested_slot)
+            }
         }
     }
 
     pub fn free_slot(&mut self, freeing_slot: &Host) {
-        self.registry.entry(freeing_slot.a)
-            .and_modify(| val | { 
-                val.entry(freeing_slot.b)
-                    .and_modify(| val2 |  {
-                         *val2 = false 
-                    }); 
-            });
+        let address = match self.interface(&freeing_slot.interface) {
+            Some(interface) => interface.host_ipv4(freeing_slot),
+            None => return
+        };
+
+        if let Some(registry) = self.registry.get_mut(&freeing_slot.interface) {
+            registry.release(address);
+        }
     }
 }